@@ -0,0 +1,1377 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream as StdUnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(unix)]
+use lamp::io::UnixStream;
+use lamp::io::{AsyncRead, AsyncWrite, TcpStream, TokenBearer};
+
+use super::dns::{Resolution, Resolve};
+use super::info::ConnectionInfo;
+use super::proxy;
+use super::proxy_header::ProxyHeader;
+use super::sockopt::SocketOptions;
+use super::socks::{Socks5, Target};
+use super::stream::AsyncShutdown;
+use super::timer::{self, Delay, Phase, Timeout, Timeouts};
+use super::tls_client::TlsClient;
+use super::tls_config::TlsPolicy;
+use super::url::{Host, Url, UrlError};
+
+/// Future establishing the TCP stream a connection runs over,
+/// either straight to the origin or tunnelled through a proxy.
+pub(crate) type Connecting<IO = Tcp> = Pin<Box<dyn Future<Output = io::Result<IO>> + Send>>;
+
+/// A stream TLS and HTTP can run over.
+pub(crate) trait Link: AsyncRead + AsyncWrite + AsyncShutdown + Unpin + Send {
+    /// Addresses of both ends, `None` when the stream isn't a TCP one we connected.
+    fn addrs(&self) -> Option<Addrs>;
+}
+
+/// Addresses of both ends of a TCP stream,
+/// the peer being the proxy when tunnelling through one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Addrs {
+    pub(crate) local: Option<SocketAddr>,
+    pub(crate) peer: SocketAddr,
+}
+
+/// A TCP stream along with what `TcpStream` doesn't tell about its socket.
+pub(crate) struct Tcp {
+    io: TcpStream,
+
+    /// Second handle on the socket, to shut its write half down.
+    socket: std::net::TcpStream,
+
+    addrs: Addrs,
+
+    /// When to look at the connect again, once the socket
+    /// turned out writable before it connected.
+    recheck: Option<Delay>,
+}
+
+impl Tcp {
+    /// Wraps a socket whose connect to `peer` was started,
+    /// its local address is bound by then.
+    fn new(socket: std::net::TcpStream, peer: SocketAddr) -> io::Result<Self> {
+        let local = socket.local_addr().ok();
+        let handle = socket.try_clone()?;
+
+        Ok(Self {
+            io: TcpStream::from_std(socket)?,
+            socket: handle,
+            addrs: Addrs { local, peer },
+            recheck: None,
+        })
+    }
+
+    pub(crate) fn addrs(&self) -> Addrs {
+        self.addrs
+    }
+
+    /// Polls a socket whose connect was started until it's connected or failed.
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(res) = self.connect_result() {
+            return Poll::Ready(res);
+        }
+
+        if self.recheck.is_some() && !timer::expired(&mut self.recheck, cx) {
+            return Poll::Pending;
+        }
+
+        self.recheck = None;
+
+        // Only used to wait for the socket to become writable,
+        // what the write returns says nothing reliable about the connect.
+        match Pin::new(&mut self.io).poll_write(cx, &[]) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(_) => match self.connect_result() {
+                Some(res) => Poll::Ready(res),
+                None => {
+                    // The write left no registration behind and would return right away
+                    // if polled again, the timer's is the one waking us up instead.
+                    self.recheck = Some(Delay::new(CONNECT_RECHECK));
+
+                    match timer::expired(&mut self.recheck, cx) {
+                        true => self.poll_connected(cx),
+                        false => Poll::Pending,
+                    }
+                }
+            },
+        }
+    }
+
+    /// Outcome of the connect, `None` while it's still in progress.
+    fn connect_result(&self) -> Option<io::Result<()>> {
+        match self.socket.take_error() {
+            Ok(Some(e)) | Err(e) => return Some(Err(e)),
+            Ok(None) => {}
+        }
+
+        match self.socket.peer_addr() {
+            Ok(_) => Some(Ok(())),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl AsyncRead for Tcp {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tcp {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+}
+
+impl AsyncShutdown for Tcp {
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(shutdown_write(self.socket.shutdown(Shutdown::Write)))
+    }
+}
+
+impl TokenBearer for Tcp {
+    fn get_token(&self) -> mio::Token {
+        self.io.get_token()
+    }
+}
+
+impl Link for Tcp {
+    fn addrs(&self) -> Option<Addrs> {
+        Some(self.addrs)
+    }
+}
+
+/// Streams a caller connected themselves can be used as.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Future returned by a `Dial` implementation.
+pub type Dialing = Pin<Box<dyn Future<Output = io::Result<Box<dyn Io>>> + Send>>;
+
+/// Opens the streams connections run over instead of dialling TCP,
+/// like in-process pipes, tunnels or forwarded channels.
+///
+/// It's asked for a new stream, given the host and port of the url,
+/// every time a connection is opened.
+pub trait Dial: Send + Sync {
+    fn dial(&self, host: &str, port: u16) -> Dialing;
+}
+
+/// A `Dial` implementation, along with an id telling it apart in the pool.
+#[derive(Clone)]
+pub(crate) struct Dialer {
+    id: u64,
+    dial: Arc<dyn Dial>,
+}
+
+impl Dialer {
+    pub(crate) fn new(dial: impl Dial + 'static) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            dial: Arc::new(dial),
+        }
+    }
+
+    /// Dials `io` for the first connection, and fails every later one.
+    pub(crate) fn once(io: impl Io + 'static) -> Self {
+        Self::new(Once(Mutex::new(Some(Box::new(io)))))
+    }
+}
+
+/// Hands out a single stream the caller connected.
+struct Once(Mutex<Option<Box<dyn Io>>>);
+
+impl Dial for Once {
+    fn dial(&self, _host: &str, _port: u16) -> Dialing {
+        let res = self.0.lock().unwrap().take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the stream the client was connected over is already used",
+            )
+        });
+
+        Box::pin(future::ready(res))
+    }
+}
+
+/// A stream the caller connected, in place of one we dial.
+pub(crate) struct Custom(Box<dyn Io>);
+
+impl AsyncRead for Custom {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Custom {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0).poll_flush(cx)
+    }
+}
+
+/// There is no telling how to half-close the caller's stream,
+/// it's only flushed and then closed when dropped.
+impl AsyncShutdown for Custom {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0).poll_flush(cx)
+    }
+}
+
+impl Link for Custom {
+    fn addrs(&self) -> Option<Addrs> {
+        None
+    }
+}
+
+/// A peer which already closed the connection is as good as shut down.
+fn shutdown_write(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        res => res,
+    }
+}
+
+/// Where connections go instead of the host and port of the url,
+/// and the name sent to the server instead of the host.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Overrides {
+    /// Addresses of a host and port, used instead of resolving it.
+    addrs: HashMap<(Host, u16), Vec<IpAddr>>,
+
+    /// Host and port connected to instead of a host and port.
+    connect_to: HashMap<(Host, u16), (Host, u16)>,
+
+    /// Name sent as SNI instead of a host.
+    server_names: HashMap<Host, Host>,
+}
+
+impl Overrides {
+    pub(crate) fn add_addrs(
+        &mut self,
+        host: &str,
+        port: u16,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<(), UrlError> {
+        let host = Host::parse(host)?;
+        self.addrs.insert((host, port), addrs.into_iter().collect());
+
+        Ok(())
+    }
+
+    pub(crate) fn add_connect_to(
+        &mut self,
+        host: &str,
+        port: u16,
+        target: &str,
+        target_port: u16,
+    ) -> Result<(), UrlError> {
+        let host = Host::parse(host)?;
+        let target = Host::parse(target)?;
+        self.connect_to.insert((host, port), (target, target_port));
+
+        Ok(())
+    }
+
+    pub(crate) fn add_server_name(
+        &mut self,
+        host: &str,
+        server_name: &str,
+    ) -> Result<(), UrlError> {
+        let host = Host::parse(host)?;
+        let server_name = Host::parse(server_name)?;
+        self.server_names.insert(host, server_name);
+
+        Ok(())
+    }
+
+    /// Host and port connections to `url` are made to.
+    fn target(&self, url: &Url) -> (Host, u16) {
+        let key = (url.host().clone(), url.port_or_default().unwrap_or(80));
+
+        match self.connect_to.get(&key) {
+            Some(target) => target.clone(),
+            None => key,
+        }
+    }
+
+    /// Name the server of `url` is told it's reached as, and its certificate checked against.
+    fn server_name<'u>(&'u self, url: &'u Url) -> &'u Host {
+        self.server_names.get(url.host()).unwrap_or(url.host())
+    }
+}
+
+/// What decides where connections to an origin go and how they're set up,
+/// connections being only shared between clients agreeing on all of it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Route {
+    proxy: Option<String>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    target: (Host, u16),
+    addrs: Option<Vec<IpAddr>>,
+    server_name: Host,
+    tls: u64,
+    socket: SocketOptions,
+    proxy_header: Option<ProxyHeader>,
+    dialer: Option<u64>,
+}
+
+/// Everything needed to open a new connection to an origin.
+///
+/// Cheap to clone, so that connections can be opened from spawned tasks.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    resolver: Arc<dyn Resolve>,
+    attempt_delay: Duration,
+    timeouts: Timeouts,
+    proxy: Option<Url>,
+    tls: TlsPolicy,
+    overrides: Arc<Overrides>,
+    socket: Arc<SocketOptions>,
+    proxy_header: Option<Arc<ProxyHeader>>,
+    dialer: Option<Dialer>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+}
+
+impl Connector {
+    pub(crate) fn new(resolver: Arc<dyn Resolve>) -> Self {
+        Self {
+            resolver,
+            attempt_delay: ATTEMPT_DELAY,
+            timeouts: Timeouts::default(),
+            proxy: None,
+            tls: TlsPolicy::default(),
+            overrides: Arc::default(),
+            socket: Arc::default(),
+            proxy_header: None,
+            dialer: None,
+            #[cfg(unix)]
+            unix_socket: None,
+        }
+    }
+
+    pub(crate) fn set_overrides(&mut self, overrides: Overrides) -> &mut Self {
+        self.overrides = Arc::new(overrides);
+        self
+    }
+
+    pub(crate) fn set_socket_options(&mut self, options: SocketOptions) -> &mut Self {
+        self.socket = Arc::new(options);
+        self
+    }
+
+    /// Sends `header` ahead of anything else on every connection,
+    /// once tunnelled through the proxy if there is one.
+    pub(crate) fn set_proxy_header(&mut self, header: Option<ProxyHeader>) -> &mut Self {
+        self.proxy_header = header.map(Arc::new);
+        self
+    }
+
+    /// Opens every connection over a stream from `dialer` instead of dialling TCP.
+    pub(crate) fn set_dialer(&mut self, dialer: Option<Dialer>) -> &mut Self {
+        self.dialer = dialer;
+        self
+    }
+
+    pub(crate) fn set_tls(&mut self, tls: TlsPolicy) -> &mut Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Tunnels every connection through the proxy at `proxy`,
+    /// a `http`, `socks5` or `socks5h` url.
+    pub(crate) fn set_proxy(&mut self, proxy: Option<Url>) -> &mut Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub(crate) fn set_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub(crate) fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Where connections to `url` go and how they're set up.
+    pub(crate) fn route(&self, url: &Url) -> Route {
+        let target = self.overrides.target(url);
+
+        Route {
+            proxy: self.proxy.as_ref().map(Url::to_string),
+            #[cfg(unix)]
+            unix_socket: self.unix_socket.clone(),
+            addrs: self.overrides.addrs.get(&target).cloned(),
+            target,
+            server_name: self.overrides.server_name(url).clone(),
+            tls: self.tls.for_host(&url.hostname()).id(),
+            socket: SocketOptions::clone(&self.socket),
+            proxy_header: self.proxy_header.as_deref().cloned(),
+            dialer: self.dialer.as_ref().map(|dialer| dialer.id),
+        }
+    }
+
+    /// Whether the first request of a connection to `url` may go out as early data.
+    pub(crate) fn sends_early_data(&self, url: &Url) -> bool {
+        #[cfg(unix)]
+        if self.unix_socket.is_some() && self.dialer.is_none() {
+            return false;
+        }
+
+        self.tls.for_host(&url.hostname()).early_data() && url.scheme() == "https"
+    }
+
+    pub(crate) fn set_attempt_delay(&mut self, delay: Duration) -> &mut Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Sends every request over the Unix domain socket at `path`
+    /// instead of connecting to the host of the url.
+    #[cfg(unix)]
+    pub(crate) fn set_unix_socket(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.unix_socket = path;
+        self
+    }
+
+    /// Resolves and connects to `url`, or to the proxy and then through it,
+    /// all of it bound by the connect timeout.
+    fn tcp(&self, url: &Url) -> io::Result<Connecting> {
+        let (host, port) = match self.proxy {
+            Some(ref proxy) => (proxy.host().clone(), proxy.port_or_default().unwrap_or(80)),
+            None => self.overrides.target(url),
+        };
+
+        let tcp = TcpConnect::with_lookup(self.lookup(&host, port))
+            .attempt_delay(self.attempt_delay)
+            .socket_options(Arc::clone(&self.socket));
+
+        let io: Connecting = match self.proxy {
+            None => Box::pin(tcp),
+            Some(ref proxy) => self.tunnel(proxy, url, tcp)?,
+        };
+
+        let io: Connecting = match self.preamble()? {
+            None => io,
+            Some(preamble) => Box::pin(async move {
+                let mut tcp = io.await?;
+                proxy::write_all(&mut tcp, &preamble).await?;
+
+                Ok(tcp)
+            }),
+        };
+
+        Ok(Box::pin(Timeout::new(
+            io,
+            self.timeouts.connect,
+            Phase::Connect,
+        )))
+    }
+
+    /// The PROXY protocol header to send first, if there is one.
+    fn preamble(&self) -> io::Result<Option<Vec<u8>>> {
+        self.proxy_header
+            .as_ref()
+            .map(|header| header.encode())
+            .transpose()
+    }
+
+    /// Resolves `host`, unless its addresses were given.
+    fn lookup(&self, host: &Host, port: u16) -> Resolution {
+        match self.overrides.addrs.get(&(host.clone(), port)) {
+            Some(ips) => {
+                let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+
+                Box::pin(future::ready(Ok(addrs)))
+            }
+            None => self.resolver.resolve(&host.name(), port),
+        }
+    }
+
+    /// Opens a tunnel to `url` through `proxy`, once `tcp` reached it.
+    fn tunnel(&self, proxy: &Url, url: &Url, tcp: TcpConnect) -> io::Result<Connecting> {
+        let (host, port) = self.overrides.target(url);
+        let auth = match (proxy.username(), proxy.password()) {
+            (Some(user), pass) => Some((user.to_string(), pass.unwrap_or("").to_string())),
+            (None, _) => None,
+        };
+
+        match proxy.scheme() {
+            "http" => Ok(Box::pin(async move {
+                proxy::tunnel(tcp.await?, &host, port).await
+            })),
+
+            // The proxy is given an address we resolved.
+            "socks5" => {
+                let lookup = self.lookup(&host, port);
+
+                Ok(Box::pin(async move {
+                    let addr = match lookup.await?.first() {
+                        Some(addr) => *addr,
+                        None => {
+                            let err = io::Error::new(
+                                io::ErrorKind::NotFound,
+                                "no addresses found for host",
+                            );
+
+                            return Err(err);
+                        }
+                    };
+
+                    Socks5::new(tcp.await?, Target::Addr(addr), auth).await
+                }))
+            }
+
+            // The proxy resolves the host itself.
+            "socks5h" => {
+                let target = match host.ip() {
+                    Some(ip) => Target::Addr(SocketAddr::new(ip, port)),
+                    None => Target::Domain(host.name(), port),
+                };
+
+                Ok(Box::pin(async move {
+                    Socks5::new(tcp.await?, target, auth).await
+                }))
+            }
+
+            _ => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "unsupported proxy scheme");
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Connects to `url`, over TLS sending `early_data` along with the handshake.
+    /// It is written either way, either as early data or right after the handshake.
+    pub(crate) async fn connect(
+        &self,
+        url: &Url,
+        early_data: Option<Vec<u8>>,
+    ) -> io::Result<Transport> {
+        if let Some(ref dialer) = self.dialer {
+            let port = url.port_or_default().unwrap_or(80);
+            let dial = dialer.dial.dial(&url.hostname(), port);
+            let io = Timeout::new(dial, self.timeouts.connect, Phase::Connect).await?;
+
+            return self.connect_over(url, Custom(io), early_data).await;
+        }
+
+        #[cfg(unix)]
+        if let Some(ref path) = self.unix_socket {
+            return match url.scheme() {
+                "http" => {
+                    let (io, socket) = unix_connect(path)?;
+
+                    Ok(Transport::Unix(io, socket))
+                }
+                _ => {
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "only http urls can be sent over a unix socket",
+                    );
+
+                    Err(err)
+                }
+            };
+        }
+
+        match url.scheme() {
+            "https" => {
+                let host = url.hostname();
+                let port = url.port_or_default().unwrap_or(443);
+                let cfg = self.tls.for_host(&host).client_config(&host, port)?;
+                let handshake = self.timeouts.handshake;
+                let server_name = self.overrides.server_name(url);
+                let tcp = self.tcp(url)?;
+                let io =
+                    TlsClient::create(cfg, url, server_name, tcp, handshake, early_data)?.await?;
+
+                Ok(Transport::Tls(io))
+            }
+
+            "http" => {
+                let io = self.tcp(url)?.await?;
+
+                Ok(Transport::Plain(io))
+            }
+
+            _ => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "unsupported url scheme");
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs the connection to `url` over `io`, which the dialer connected,
+    /// with a TLS handshake first for `https` urls.
+    ///
+    /// Proxies, overrides of the target and the Unix socket don't apply,
+    /// the server name and the PROXY protocol header do.
+    async fn connect_over(
+        &self,
+        url: &Url,
+        mut io: Custom,
+        early_data: Option<Vec<u8>>,
+    ) -> io::Result<Transport> {
+        if let Some(preamble) = self.preamble()? {
+            proxy::write_all(&mut io, &preamble).await?;
+        }
+
+        match url.scheme() {
+            "https" => {
+                let host = url.hostname();
+                let port = url.port_or_default().unwrap_or(443);
+                let cfg = self.tls.for_host(&host).client_config(&host, port)?;
+                let handshake = self.timeouts.handshake;
+                let server_name = self.overrides.server_name(url);
+                let link: Connecting<Custom> = Box::pin(future::ready(Ok(io)));
+                let io =
+                    TlsClient::create(cfg, url, server_name, link, handshake, early_data)?.await?;
+
+                Ok(Transport::CustomTls(io))
+            }
+
+            "http" => Ok(Transport::Custom(io)),
+
+            _ => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "unsupported url scheme");
+
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Connects to a local Unix domain socket.
+///
+/// Connecting to a listening Unix socket completes right away,
+/// so unlike TCP there is nothing to wait on.
+#[cfg(unix)]
+fn unix_connect(path: &Path) -> io::Result<(UnixStream, StdUnixStream)> {
+    let io = StdUnixStream::connect(path)?;
+    io.set_nonblocking(true)?;
+
+    let handle = io.try_clone()?;
+
+    Ok((UnixStream::from_std(io)?, handle))
+}
+
+/// Transport of a HTTP connection.
+pub(crate) enum Transport {
+    Tls(TlsClient),
+    Plain(Tcp),
+
+    /// The stream and a second handle on its socket.
+    #[cfg(unix)]
+    Unix(UnixStream, StdUnixStream),
+
+    /// A stream the caller connected, with TLS over it for `https` urls.
+    Custom(Custom),
+    CustomTls(TlsClient<Custom>),
+}
+
+impl Transport {
+    /// Details of the connection, taken once it's established.
+    pub(crate) fn info(&self) -> ConnectionInfo {
+        match self {
+            Transport::Tls(io) => io.info(),
+            Transport::Plain(io) => ConnectionInfo::new(Some(io.addrs()), None),
+            #[cfg(unix)]
+            Transport::Unix(..) => ConnectionInfo::default(),
+            Transport::Custom(_) => ConnectionInfo::default(),
+            Transport::CustomTls(io) => io.info(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tls(io) => Pin::new(io).poll_read(cx, buf),
+            Transport::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(io, _) => Pin::new(io).poll_read(cx, buf),
+            Transport::Custom(io) => Pin::new(io).poll_read(cx, buf),
+            Transport::CustomTls(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tls(io) => Pin::new(io).poll_write(cx, buf),
+            Transport::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(io, _) => Pin::new(io).poll_write(cx, buf),
+            Transport::Custom(io) => Pin::new(io).poll_write(cx, buf),
+            Transport::CustomTls(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tls(io) => Pin::new(io).poll_flush(cx),
+            Transport::Plain(io) => Pin::new(io).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(io, _) => Pin::new(io).poll_flush(cx),
+            Transport::Custom(io) => Pin::new(io).poll_flush(cx),
+            Transport::CustomTls(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+}
+
+impl AsyncShutdown for Transport {
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tls(io) => Pin::new(io).poll_shutdown(cx),
+            Transport::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(_, socket) => {
+                Poll::Ready(shutdown_write(socket.shutdown(Shutdown::Write)))
+            }
+            Transport::Custom(io) => Pin::new(io).poll_shutdown(cx),
+            Transport::CustomTls(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Delay between two connection attempts, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Lower bound of the delay between two attempts, RFC 8305 section 5.
+const MIN_ATTEMPT_DELAY: Duration = Duration::from_millis(10);
+
+/// Delay before looking at a connect again when the socket
+/// reported writable while still connecting.
+const CONNECT_RECHECK: Duration = Duration::from_millis(10);
+
+enum State {
+    Resolving(Resolution),
+    Connecting,
+    Done,
+}
+
+/// Future resolving a host and establishing a TCP connection to it
+/// without blocking the executor.
+///
+/// Connection attempts are raced as described by RFC 8305 (Happy Eyeballs):
+/// addresses are interleaved by family, a new attempt is started every
+/// `attempt_delay` or as soon as the previous one fails,
+/// and the first attempt to connect wins.
+pub(crate) struct TcpConnect {
+    state: State,
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<Tcp>,
+    next_attempt: Option<Delay>,
+    attempt_delay: Duration,
+    options: Arc<SocketOptions>,
+    last_err: Option<io::Error>,
+}
+
+impl TcpConnect {
+    pub(crate) fn new(host: &str, port: u16, resolver: &dyn Resolve) -> Self {
+        Self::with_lookup(resolver.resolve(host, port))
+    }
+
+    /// Connects to the addresses `lookup` resolves to.
+    pub(crate) fn with_lookup(lookup: Resolution) -> Self {
+        TcpConnect {
+            state: State::Resolving(lookup),
+            addrs: VecDeque::new(),
+            attempts: Vec::new(),
+            next_attempt: None,
+            attempt_delay: ATTEMPT_DELAY,
+            options: Arc::default(),
+            last_err: None,
+        }
+    }
+
+    /// Sets the delay before racing the next address against pending attempts.
+    pub(crate) fn attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay.max(MIN_ATTEMPT_DELAY);
+        self
+    }
+
+    /// Sets the options of the sockets the attempts are made from.
+    pub(crate) fn socket_options(mut self, options: Arc<SocketOptions>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Starts a non-blocking connect to the next address in line.
+    /// Returns `false` if there are no addresses left.
+    fn start_attempt(&mut self) -> bool {
+        while let Some(addr) = self.addrs.pop_front() {
+            let res = self
+                .options
+                .connect(addr)
+                .and_then(|socket| Tcp::new(socket, addr));
+
+            match res {
+                Ok(attempt) => {
+                    self.attempts.push(attempt);
+                    self.next_attempt = Some(Delay::new(self.attempt_delay));
+
+                    return true;
+                }
+                Err(e) => self.last_err = Some(e),
+            }
+        }
+
+        false
+    }
+
+    /// Polls every pending attempt, returning the first one that connected.
+    /// Sets `failed` if any of them failed.
+    fn poll_attempts(&mut self, cx: &mut Context<'_>, failed: &mut bool) -> Option<Tcp> {
+        let mut index = 0;
+
+        while index < self.attempts.len() {
+            match self.attempts[index].poll_connected(cx) {
+                Poll::Pending => index += 1,
+                Poll::Ready(Ok(_)) => return Some(self.attempts.swap_remove(index)),
+                Poll::Ready(Err(e)) => {
+                    self.last_err = Some(e);
+                    self.attempts.swap_remove(index);
+                    *failed = true;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Orders addresses as per RFC 8305 section 4,
+/// alternating families starting with the one the resolver preferred.
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return VecDeque::new(),
+    };
+
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut out = VecDeque::with_capacity(first.len() + second.len());
+
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => a.into_iter().chain(b).for_each(|addr| out.push_back(addr)),
+        }
+    }
+
+    out
+}
+
+impl Future for TcpConnect {
+    type Output = io::Result<Tcp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        use std::mem;
+
+        let me = &mut *self;
+
+        loop {
+            match mem::replace(&mut me.state, State::Done) {
+                State::Done => panic!("polled after completion"),
+
+                State::Resolving(mut lookup) => match lookup.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        me.state = State::Resolving(lookup);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Ok(addrs)) => {
+                        me.addrs = interleave(addrs);
+                        me.start_attempt();
+                        me.state = State::Connecting;
+                    }
+                },
+
+                State::Connecting => {
+                    let mut failed = false;
+
+                    if let Some(io) = me.poll_attempts(cx, &mut failed) {
+                        // Losing attempts are closed when dropped.
+                        me.attempts.clear();
+
+                        return Poll::Ready(Ok(io));
+                    }
+
+                    let delay_passed = match me.next_attempt.as_mut() {
+                        Some(delay) => Pin::new(delay).poll(cx).is_ready(),
+                        None => false,
+                    };
+
+                    me.state = State::Connecting;
+
+                    // A failed attempt starts the next one right away,
+                    // otherwise wait for the attempt delay to pass.
+                    if (failed || delay_passed || me.attempts.is_empty()) && me.start_attempt() {
+                        continue;
+                    }
+
+                    if delay_passed {
+                        me.next_attempt = None;
+                    }
+
+                    if me.attempts.is_empty() {
+                        let err = match me.last_err.take() {
+                            Some(e) => e,
+                            None => io::Error::new(
+                                io::ErrorKind::NotFound,
+                                "no addresses to connect to",
+                            ),
+                        };
+
+                        me.state = State::Done;
+                        return Poll::Ready(Err(err));
+                    }
+
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dial, Dialing, Io, TcpConnect, interleave};
+    use crate::dns::{self, Resolution, Resolve};
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{OK, USER_AGENT, ca_config, run, server_config, tls_server_with};
+    use crate::url::Url;
+    use std::io::{self, Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, mpsc};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    #[cfg(unix)]
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolve for StaticResolver {
+        fn resolve(&self, _host: &str, _port: u16) -> Resolution {
+            Box::pin(std::future::ready(Ok(self.0.clone())))
+        }
+    }
+
+    /// Answers one request on `sock` with `200 ok` and closes the connection.
+    #[cfg(unix)]
+    fn serve_once<S: Read + Write>(sock: &mut S) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        let _ = sock.read(&mut buf)?;
+
+        sock.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok")?;
+        sock.flush()
+    }
+
+    /// Like `tls_server`, sending the SNI and `Host` header of the request it got to `report`.
+    fn tls_server_reporting(
+        report: mpsc::Sender<(Option<String>, String)>,
+    ) -> (u16, JoinHandle<io::Result<()>>) {
+        tls_server_with(false, move |tls| {
+            let mut buf = [0u8; 1024];
+            let len = tls.read(&mut buf)?;
+
+            let head = String::from_utf8_lossy(&buf[..len]);
+            let host = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Host: "))
+                .unwrap_or_default();
+
+            let sni = tls.conn.server_name().map(str::to_string);
+            let _ = report.send((sni, host.to_string()));
+
+            tls.write_all(OK)?;
+            tls.flush()
+        })
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let ordered: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+
+        assert_eq!(
+            ordered,
+            ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+
+        let addrs = vec!["10.0.0.1:1".parse().unwrap(), "[::1]:1".parse().unwrap()];
+        assert!(interleave(addrs)[0].is_ipv4());
+    }
+
+    #[test]
+    fn tcp_connect_to_local_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        run(async move {
+            let tcp = TcpConnect::new("localhost", port, &dns::GaiResolver).await;
+            assert!(tcp.is_ok(), "failed to connect to local listener");
+
+            let accepted = listener.accept();
+            assert!(accepted.is_ok(), "listener didn't see the connection");
+        });
+    }
+
+    #[test]
+    fn happy_eyeballs_prefers_first_family() {
+        let v6 = TcpListener::bind("[::1]:0").unwrap();
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let resolver = StaticResolver(vec![v6.local_addr().unwrap(), v4.local_addr().unwrap()]);
+
+        run(async move {
+            let tcp = TcpConnect::new("dual.test", 0, &resolver).await;
+            assert!(tcp.is_ok(), "failed to connect to either listener");
+        });
+
+        v4.set_nonblocking(true).unwrap();
+        assert!(
+            v6.accept().is_ok(),
+            "IPv6 listener didn't see the connection"
+        );
+        assert!(v4.accept().is_err(), "IPv4 was raced needlessly");
+    }
+
+    #[test]
+    fn happy_eyeballs_falls_back() {
+        // Nothing listens on this port anymore.
+        let dead = TcpListener::bind("[::1]:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        drop(dead);
+
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let resolver = StaticResolver(vec![dead_addr, v4.local_addr().unwrap()]);
+
+        run(async move {
+            let start = Instant::now();
+
+            let tcp = TcpConnect::new("dual.test", 0, &resolver)
+                .attempt_delay(Duration::from_secs(2))
+                .await;
+
+            assert!(tcp.is_ok(), "didn't fall back to IPv4");
+
+            // A refused attempt starts the next one without waiting for the delay.
+            assert!(start.elapsed() < Duration::from_secs(2));
+        });
+
+        assert!(
+            v4.accept().is_ok(),
+            "IPv4 listener didn't see the connection"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn request_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("tunnel-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+
+            let mut buf = [0u8; 1024];
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"GET /_ping HTTP/1.1\r\nHost: localhost\r\n"));
+
+            let resp = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
+            sock.write_all(resp.as_bytes()).unwrap();
+        });
+
+        let socket = path.clone();
+
+        run(async move {
+            let url = Url::parse("http://localhost/_ping").unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .unix_socket(socket)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+            assert!(resp.connection().unwrap().peer_addr().is_none());
+            assert_eq!(resp.content(), Some("OK".as_bytes()));
+        });
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_over_supplied_streams() {
+        fn serve<S: Read + Write>(sock: &mut S) -> io::Result<()> {
+            let mut buf = [0u8; 1024];
+            let len = sock.read(&mut buf)?;
+            assert!(buf[..len].starts_with(b"GET / HTTP/1.1\r\nHost: localhost:1\r\n"));
+
+            sock.write_all(OK)?;
+            sock.flush()
+        }
+
+        let (plain, mut plain_peer) = UnixStream::pair().unwrap();
+        let (tls, tls_peer) = UnixStream::pair().unwrap();
+        plain.set_nonblocking(true).unwrap();
+        tls.set_nonblocking(true).unwrap();
+
+        let plain_server = thread::spawn(move || serve(&mut plain_peer));
+        let tls_server = thread::spawn(move || {
+            let conn = rustls::ServerConnection::new(Arc::new(server_config(false))).unwrap();
+            serve(&mut rustls::StreamOwned::new(conn, tls_peer))
+        });
+
+        run(async move {
+            // Nothing listens on the port, every request goes over the streams given.
+            for (url, io) in [
+                ("http://localhost:1/", plain),
+                ("https://localhost:1/", tls),
+            ] {
+                let url = Url::parse(url).unwrap();
+                let io = lamp::io::UnixStream::from_std(io).unwrap();
+
+                let mut client = ClientBuilder::new(USER_AGENT)
+                    .tls(ca_config())
+                    .connect_over(&url, io)
+                    .await
+                    .unwrap();
+
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+                assert_eq!(resp.content(), Some("ok".as_bytes()));
+
+                let info = resp.connection().unwrap();
+                assert!(info.peer_addr().is_none());
+                assert_eq!(info.tls().is_some(), url.scheme() == "https");
+            }
+        });
+
+        plain_server.join().unwrap().unwrap();
+        tls_server.join().unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn supplied_stream_not_redialled() {
+        let (io, mut peer) = UnixStream::pair().unwrap();
+        io.set_nonblocking(true).unwrap();
+
+        let server = thread::spawn(move || serve_once(&mut peer));
+
+        run(async move {
+            let url = Url::parse("http://localhost:1/").unwrap();
+            let io = lamp::io::UnixStream::from_std(io).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .connect_over(&url, io)
+                .await
+                .unwrap();
+
+            let resp = client.execute(ReqBuilder::new(Method::GET)).await.unwrap();
+            assert_eq!(resp.unwrap().code(), 200);
+
+            // The stream is closed, nothing may be dialled in its place.
+            let err = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        });
+
+        server.join().unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dialer_called_per_connection() {
+        struct Pipes(Arc<AtomicUsize>);
+
+        impl Dial for Pipes {
+            fn dial(&self, host: &str, port: u16) -> Dialing {
+                assert_eq!((host, port), ("localhost", 1));
+                self.0.fetch_add(1, Ordering::AcqRel);
+
+                let (io, mut peer) = UnixStream::pair().unwrap();
+                io.set_nonblocking(true).unwrap();
+                thread::spawn(move || serve_once(&mut peer));
+
+                Box::pin(async move {
+                    let io = lamp::io::UnixStream::from_std(io)?;
+
+                    Ok(Box::new(io) as Box<dyn Io>)
+                })
+            }
+        }
+
+        let dialled = Arc::new(AtomicUsize::new(0));
+        let pipes = Pipes(Arc::clone(&dialled));
+
+        run(async move {
+            let url = Url::parse("http://localhost:1/").unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .dialer(pipes)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            for _ in 0..2 {
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+                assert_eq!(resp.content(), Some("ok".as_bytes()));
+            }
+        });
+
+        assert_eq!(dialled.load(Ordering::Acquire), 2);
+    }
+
+    #[test]
+    fn connect_and_sni_overrides() {
+        let (report, reported) = mpsc::channel();
+        let (resolve_port, resolve_server) = tls_server_reporting(report.clone());
+        let (connect_port, connect_server) = tls_server_reporting(report);
+
+        run(async move {
+            let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+            // A host without DNS, its certificate being the one of `localhost`.
+            let url = Url::parse(&format!("https://stand-in.test:{}/", resolve_port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(ca_config())
+                .resolve("stand-in.test", resolve_port, [localhost])
+                .unwrap()
+                .server_name("stand-in.test", "localhost")
+                .unwrap()
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client.execute(ReqBuilder::new(Method::GET)).await;
+            assert_eq!(resp.unwrap().unwrap().code(), 200);
+
+            // Nothing listens on the port of the url.
+            let url = Url::parse("https://localhost:1/").unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(ca_config())
+                .connect_to("localhost", 1, "127.0.0.1", connect_port)
+                .unwrap()
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client.execute(ReqBuilder::new(Method::GET)).await;
+            assert_eq!(resp.unwrap().unwrap().code(), 200);
+
+            assert!(
+                ClientBuilder::new(USER_AGENT)
+                    .connect_to("localhost", 1, "not a host", 2)
+                    .is_err()
+            );
+        });
+
+        resolve_server.join().unwrap().unwrap();
+        connect_server.join().unwrap().unwrap();
+
+        let expected = [
+            (
+                Some("localhost".to_string()),
+                format!("stand-in.test:{}", resolve_port),
+            ),
+            (Some("localhost".to_string()), "localhost:1".to_string()),
+        ];
+
+        assert_eq!(reported.try_iter().collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,332 @@
+use futures::channel::oneshot;
+use std::collections::HashMap;
+use std::fs;
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+type Addrs = io::Result<Vec<SocketAddr>>;
+
+/// Future returned by a `Resolve` implementation.
+pub type Resolution = Pin<Box<dyn Future<Output = Addrs> + Send>>;
+
+/// Resolves host names into socket addresses.
+///
+/// A resolver must never block the calling thread,
+/// any blocking work has to happen inside the returned future
+/// or off the executor entirely.
+pub trait Resolve: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> Resolution;
+}
+
+/// Future resolving a host name to a list of socket addresses.
+///
+/// The lookup itself is performed on a separate thread,
+/// so `getaddrinfo` never blocks a worker of the executor.
+pub(crate) struct Lookup {
+    recv: oneshot::Receiver<Addrs>,
+}
+
+impl Lookup {
+    pub(crate) fn new(host: &str, port: u16) -> io::Result<Self> {
+        let (sender, recv) = oneshot::channel();
+
+        // IP literals don't need a resolver at all.
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            let _ = sender.send(Ok(vec![SocketAddr::new(ip, port)]));
+
+            return Ok(Lookup { recv });
+        }
+
+        let host = host.to_string();
+
+        thread::Builder::new()
+            .name("tunnel-dns".to_string())
+            .spawn(move || {
+                let res = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|iter| iter.collect::<Vec<SocketAddr>>());
+
+                let _ = sender.send(res);
+            })?;
+
+        Ok(Lookup { recv })
+    }
+}
+
+impl Future for Lookup {
+    type Output = Addrs;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.recv).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Ok(addrs))) if addrs.is_empty() => {
+                let err = io::Error::new(io::ErrorKind::NotFound, "no addresses found for host");
+
+                Poll::Ready(Err(err))
+            }
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            Poll::Ready(Err(_canceled)) => {
+                let err = io::Error::other("resolver thread died");
+
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+/// Resolver using the system's `getaddrinfo` on a dedicated thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GaiResolver;
+
+impl Resolve for GaiResolver {
+    fn resolve(&self, host: &str, port: u16) -> Resolution {
+        match Lookup::new(host, port) {
+            Ok(lookup) => Box::pin(lookup),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolver answering from a hosts file first,
+/// then from a cache of previous answers of the inner resolver.
+///
+/// Answers of the inner resolver are kept for `ttl`,
+/// entries from the hosts file never expire.
+pub struct CachingResolver<R = GaiResolver> {
+    inner: R,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    ttl: Duration,
+}
+
+impl CachingResolver<GaiResolver> {
+    /// Creates a caching resolver over `getaddrinfo`, honouring `/etc/hosts`.
+    pub fn new() -> Self {
+        let mut resolver = Self::with_inner(GaiResolver);
+
+        // A missing hosts file just means there are no static entries.
+        let _ = resolver.load_hosts("/etc/hosts");
+
+        resolver
+    }
+}
+
+/// Resolver of the clients not given one, shared so that answers outlive any one client
+/// and `/etc/hosts` is only read once.
+pub(crate) fn default_resolver() -> Arc<dyn Resolve> {
+    static DEFAULT: OnceLock<Arc<CachingResolver>> = OnceLock::new();
+
+    let resolver = DEFAULT.get_or_init(|| Arc::new(CachingResolver::new()));
+    Arc::clone(resolver) as Arc<dyn Resolve>
+}
+
+impl Default for CachingResolver<GaiResolver> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Resolve> CachingResolver<R> {
+    /// Creates a caching resolver over `inner` with no static entries.
+    pub fn with_inner(inner: R) -> Self {
+        Self {
+            inner,
+            hosts: HashMap::new(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Adds the entries of a hosts file, in the `/etc/hosts` format.
+    pub fn load_hosts(&mut self, path: impl AsRef<Path>) -> io::Result<&mut Self> {
+        let text = fs::read_to_string(path)?;
+        self.add_hosts(&text);
+
+        Ok(self)
+    }
+
+    /// Adds the entries of a hosts file given as a string.
+    pub fn add_hosts(&mut self, text: &str) -> &mut Self {
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+
+            let mut fields = line.split_whitespace();
+
+            let ip = match fields.next().map(|ip| ip.parse::<IpAddr>()) {
+                Some(Ok(ip)) => ip,
+                _ => continue,
+            };
+
+            fields.for_each(|name| {
+                let ips = self.hosts.entry(name.to_ascii_lowercase()).or_default();
+
+                if !ips.contains(&ip) {
+                    ips.push(ip);
+                }
+            });
+        }
+
+        self
+    }
+
+    /// Drops every cached answer, static entries are kept.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn lookup_cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        if let Some(ips) = self.hosts.get(host) {
+            return Some(ips.clone());
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+
+        match cache.get(host) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.ips.clone()),
+            Some(_) => {
+                cache.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl<R: Resolve> Resolve for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> Resolution {
+        let host = host.to_ascii_lowercase();
+
+        if let Some(ips) = self.lookup_cached(&host) {
+            let addrs = ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+
+            return Box::pin(future::ready(Ok(addrs)));
+        }
+
+        let lookup = self.inner.resolve(&host, port);
+        let cache = Arc::clone(&self.cache);
+        let ttl = self.ttl;
+
+        Box::pin(async move {
+            let addrs = lookup.await?;
+
+            let entry = CacheEntry {
+                ips: addrs.iter().map(|addr| addr.ip()).collect(),
+                expires: Instant::now() + ttl,
+            };
+
+            cache.lock().unwrap().insert(host, entry);
+
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachingResolver, Resolution, Resolve, default_resolver};
+    use futures::executor::block_on;
+    use std::future;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    struct StubResolver {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Resolve for StubResolver {
+        fn resolve(&self, _host: &str, port: u16) -> Resolution {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+            Box::pin(future::ready(Ok(vec![addr])))
+        }
+    }
+
+    fn stub() -> (CachingResolver<StubResolver>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let stub = StubResolver {
+            calls: Arc::clone(&calls),
+        };
+
+        (CachingResolver::with_inner(stub), calls)
+    }
+
+    #[test]
+    fn cache_hit() {
+        let (resolver, calls) = stub();
+
+        let first = block_on(resolver.resolve("example.com", 443)).unwrap();
+        let second = block_on(resolver.resolve("Example.COM", 8080)).unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(first[0].ip(), second[0].ip());
+        assert_eq!(second[0].port(), 8080);
+    }
+
+    #[test]
+    fn cache_expiry() {
+        let (mut resolver, calls) = stub();
+        resolver.set_ttl(Duration::ZERO);
+
+        block_on(resolver.resolve("example.com", 443)).unwrap();
+        block_on(resolver.resolve("example.com", 443)).unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn hosts_file() {
+        let (mut resolver, calls) = stub();
+        resolver.add_hosts(concat!(
+            "# comment line\n",
+            "127.0.0.1 localhost loopback\n",
+            "::1       localhost # trailing comment\n",
+            "not-an-ip broken\n",
+        ));
+
+        let addrs = block_on(resolver.resolve("LocalHost", 80)).unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].ip().is_loopback() && addrs[1].ip().is_loopback());
+
+        block_on(resolver.resolve("broken", 80)).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn default_resolver_shared() {
+        let first = Arc::as_ptr(&default_resolver()) as *const ();
+        let second = Arc::as_ptr(&default_resolver()) as *const ();
+
+        assert_eq!(first, second);
+    }
+}
@@ -1,12 +1,30 @@
+use super::headers::{ConnectionState, Header};
+use super::pool::{ConnState, Pool};
 use super::request::{HeaderList, ReqBuilder};
 use super::response::{DataDecoder, Response};
-use crate::tls_client::{Resolving, TlsClient};
-use futures::channel::oneshot;
+use crate::connect::{Connector, Dial, Dialer, Overrides};
+use crate::dns::{self, Resolve};
+use crate::info::ConnectionInfo;
+use crate::proxy_header::ProxyHeader;
+use crate::sockopt::SocketOptions;
+use crate::stream::AsyncShutdown;
+use crate::timer::{self, Delay, Phase, Timeouts};
+use crate::tls_client::Resolving;
+use crate::tls_config::{TlsConfig, TlsPolicy};
+use crate::url::{Url, UrlError};
+use futures::channel::{mpsc, oneshot};
+use lamp::io::{AsyncRead, AsyncWrite};
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::io;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::mpsc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Method {
@@ -31,22 +49,41 @@ impl Method {
             Method::CONNECT => const { "CONNECT ".as_bytes() },
         }
     }
+
+    /// Whether the request may be sent more than once, as early data an attacker
+    /// can replay or again after its connection was lost. Only methods idempotent
+    /// by RFC 9110 qualify, here `GET`, `PUT`, `HEAD` and `OPTIONS`.
+    pub const fn is_replayable(&self) -> bool {
+        matches!(
+            *self,
+            Method::GET | Method::PUT | Method::HEAD | Method::OPTIONS
+        )
+    }
 }
 
 pub(crate) struct Connecting<'c> {
-    tls: Resolving<'c>,
+    tls: Resolving,
     user_agent: Option<&'static str>,
     headers: Option<HeaderList<'c>>,
 }
 
 #[derive(Debug)]
-struct Envelope {
+pub(crate) struct Envelope {
     data: Vec<u8>,
     oneshot: Option<oneshot::Sender<io::Result<Response>>>,
+
+    /// Deadline of the whole request, if any.
+    deadline: Option<Instant>,
+
+    /// Whether the request can be sent twice, and so as early data.
+    replayable: bool,
+
+    /// Whether the request was sent as early data while connecting.
+    sent_early: bool,
 }
 
 impl Envelope {
-    fn chan_fn<F, T>(&mut self, f: F) -> T
+    pub(crate) fn chan_fn<F, T>(&mut self, f: F) -> T
     where
         F: FnOnce(oneshot::Sender<io::Result<Response>>) -> T,
     {
@@ -54,17 +91,100 @@ impl Envelope {
         let val = f(chan);
         val
     }
+
+    /// The request to send as early data, if it's safe to replay.
+    pub(crate) fn early_data(&mut self) -> Option<Vec<u8>> {
+        self.sent_early = self.replayable;
+        self.replayable.then(|| self.data.clone())
+    }
+
+    /// The connection the request was sent early on went away before it got it.
+    pub(crate) fn not_sent_early(&mut self) {
+        self.sent_early = false;
+    }
+
+    fn is_abandoned(&self) -> bool {
+        self.oneshot.as_ref().is_none_or(|chan| chan.is_canceled())
+    }
+}
+
+/// Failure of a request on a keep-alive connection the server closed before answering,
+/// likely while the request was on its way, or before it was even written.
+/// The request can be sent again on a new connection.
+#[derive(Debug)]
+struct Stale {
+    err: io::Error,
+
+    /// Whether the request went out, and so may have been processed.
+    written: bool,
+}
+
+impl Stale {
+    fn wrap(err: io::Error) -> io::Error {
+        io::Error::new(err.kind(), Stale { err, written: true })
+    }
+
+    /// The error of a request that never left the queue of the connection.
+    fn unsent(err: io::Error) -> io::Error {
+        io::Error::new(
+            err.kind(),
+            Stale {
+                err,
+                written: false,
+            },
+        )
+    }
+
+    /// The error of a stale connection, unwrapped, if `err` is one,
+    /// along with whether the request was written.
+    fn unwrap(err: io::Error) -> Result<(io::Error, bool), io::Error> {
+        if !err.get_ref().is_some_and(|e| e.is::<Stale>()) {
+            return Err(err);
+        }
+
+        let stale = err.into_inner().expect("checked").downcast::<Stale>();
+        let stale = stale.expect("checked");
+
+        Ok((stale.err, stale.written))
+    }
+}
+
+impl fmt::Display for Stale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection closed by the server: {}", self.err)
+    }
 }
 
-// todo
-struct State;
+impl Error for Stale {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a request.
+    Idle,
+
+    /// Writing the request, with the amount of bytes written so far.
+    Writing(usize),
+
+    /// Flushing the written request.
+    Flushing,
 
-pub struct HttpsConn<'h> {
-    /// TLS connection,
-    io: TlsClient<'h>,
+    /// Reading the response.
+    Reading,
+
+    /// Closing the connection, with `close_notify` first over TLS.
+    Closing,
+}
+
+pub struct HttpsConn<IO> {
+    /// Transport, either TLS or cleartext.
+    io: IO,
 
     /// Receiver
-    recv: mpsc::Receiver<Envelope>,
+    recv: mpsc::UnboundedReceiver<Envelope>,
 
     /// Slot for currently processed request
     chan: Option<Envelope>,
@@ -76,147 +196,500 @@ pub struct HttpsConn<'h> {
     decoder: DataDecoder,
 
     /// Reciever for a notification to shutdown
-    shutdown: mpsc::Receiver<()>,
+    shutdown: Option<oneshot::Receiver<()>>,
+
+    /// State shared with the pool.
+    shared: Arc<ConnState>,
+
+    /// Deadlines of the request phases.
+    timeouts: Timeouts,
+
+    /// Deadline of the current phase, either writing or waiting for the first byte.
+    timer: Option<Delay>,
+
+    /// Deadline of the current request as a whole.
+    total: Option<Delay>,
+
+    /// Deadline of waiting for the next request.
+    idle: Option<Delay>,
+
+    /// How long the connection may wait for the next request before closing.
+    idle_timeout: Duration,
+
+    /// Amount of requests answered so far.
+    served: usize,
+
+    /// Whether the server started answering the current request.
+    answering: bool,
+
+    /// Details of the connection, attached to every response.
+    info: Arc<ConnectionInfo>,
+}
+
+impl<IO> HttpsConn<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsyncShutdown + Unpin,
+{
+    pub(crate) fn new(
+        io: IO,
+        recv: mpsc::UnboundedReceiver<Envelope>,
+        shutdown: oneshot::Receiver<()>,
+        shared: Arc<ConnState>,
+        timeouts: Timeouts,
+        idle_timeout: Duration,
+        info: ConnectionInfo,
+    ) -> Self {
+        Self {
+            io,
+            recv,
+            chan: None,
+            state: State::Idle,
+            decoder: DataDecoder::new(),
+            shutdown: Some(shutdown),
+            shared,
+            timeouts,
+            timer: None,
+            total: None,
+            idle: None,
+            idle_timeout,
+            served: 0,
+            answering: false,
+            info: Arc::new(info),
+        }
+    }
+
+    /// Fails the request in progress with `err`, the connection is unusable afterwards.
+    ///
+    /// Losing a reused connection before any of the response came
+    /// most likely means the server closed it while the request was on its way.
+    fn fail(&mut self, err: io::Error) -> Poll<io::Result<()>> {
+        use io::ErrorKind::*;
+
+        self.shared.close();
+
+        let copy = io::Error::new(err.kind(), err.to_string());
+        let closed = matches!(
+            err.kind(),
+            UnexpectedEof | ConnectionReset | ConnectionAborted | BrokenPipe
+        );
+
+        let err = match self.served > 0 && !self.answering && closed {
+            true => Stale::wrap(err),
+            false => err,
+        };
+
+        if let Some(mut envl) = self.chan.take() {
+            let _ = envl.chan_fn(|ch| ch.send(Err(err)));
+        }
+
+        self.drop_queued();
+
+        Poll::Ready(Err(copy))
+    }
+
+    /// Fails the requests queued but not sent yet, so they can be sent elsewhere,
+    /// whatever their method as the server never saw them.
+    fn drop_queued(&mut self) {
+        self.recv.close();
+
+        while let Ok(mut envl) = self.recv.try_recv() {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed");
+
+            // Except for one which went out along with the handshake.
+            let err = match envl.sent_early {
+                true => Stale::wrap(err),
+                false => Stale::unsent(err),
+            };
+
+            let _ = envl.chan_fn(|ch| ch.send(Err(err)));
+        }
+    }
+
+    /// Whether the server closed the connection while it was idle,
+    /// failing if it sent something while no request was asked.
+    fn closed_while_idle(&mut self, cx: &mut Context<'_>) -> io::Result<bool> {
+        let mut buf = [0; 1];
+
+        match Pin::new(&mut self.io).poll_read(cx, &mut buf) {
+            Poll::Pending => Ok(false),
+            Poll::Ready(Ok(0)) | Poll::Ready(Err(_)) => Ok(true),
+            Poll::Ready(Ok(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data received from the server with no request sent",
+            )),
+        }
+    }
+
+    /// Moves on to closing the connection gracefully,
+    /// a request still in progress is dropped.
+    fn close(&mut self) {
+        self.chan = None;
+        self.timer = None;
+        self.total = None;
+        self.state = State::Closing;
+    }
 }
 
-impl<'h> Future for HttpsConn<'h> {
+impl<IO> Future for HttpsConn<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsyncShutdown + Unpin,
+{
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        use lamp::io::{AsyncRead, AsyncWrite};
-        use mpsc::TryRecvError::{Disconnected, Empty};
+        use futures::Stream;
+
+        let me = &mut *self;
+
+        if let Some(shutdown) = me.shutdown.as_mut() {
+            match Pin::new(shutdown).poll(cx) {
+                Poll::Ready(Ok(())) => {
+                    me.shutdown = None;
+                    me.close();
+                }
 
-        let mut envl = if self.chan.is_some() {
-            self.chan.take().unwrap()
-        } else {
-            match self.recv.try_recv() {
-                Ok(envl) => envl,
+                // The pool is gone, finish what's queued and let the channel close.
+                Poll::Ready(Err(_canceled)) => me.shutdown = None,
+                Poll::Pending => {}
+            }
+        }
 
-                Err(e) => match e {
-                    Empty => return Poll::Pending,
+        loop {
+            if !matches!(me.state, State::Idle | State::Closing) {
+                if timer::expired(&mut me.total, cx) {
+                    return me.fail(timer::elapsed(Phase::Total));
+                }
 
-                    Disconnected => {
-                        let err = io::Error::new(io::ErrorKind::Other, "chan disconnected");
+                if timer::expired(&mut me.timer, cx) {
+                    let phase = match me.state {
+                        State::Reading => Phase::FirstByte,
+                        _ => Phase::Write,
+                    };
 
-                        return Poll::Ready(Err(err));
+                    return me.fail(timer::elapsed(phase));
+                }
+            }
+
+            match me.state {
+                State::Idle => match Pin::new(&mut me.recv).poll_next(cx) {
+                    Poll::Pending => {
+                        if me.served > 0 {
+                            match me.closed_while_idle(cx) {
+                                Ok(false) => {}
+                                Ok(true) => {
+                                    me.shared.close();
+                                    me.drop_queued();
+
+                                    return Poll::Ready(Ok(()));
+                                }
+                                Err(e) => return me.fail(e),
+                            }
+                        }
+
+                        if me.idle.is_none() {
+                            me.idle = Some(Delay::new(me.idle_timeout));
+                        }
+
+                        if !timer::expired(&mut me.idle, cx) {
+                            return Poll::Pending;
+                        }
+
+                        // Whatever got queued meanwhile is still answered.
+                        me.idle = None;
+                        me.shared.close();
+                        me.recv.close();
+                    }
+                    Poll::Ready(None) => me.close(),
+                    // The caller stopped waiting, likely on the total deadline.
+                    Poll::Ready(Some(envl)) if envl.is_abandoned() => me.shared.done(),
+                    Poll::Ready(Some(envl)) => {
+                        me.idle = None;
+                        me.total = envl.deadline.map(Delay::until);
+
+                        // Already written along with the handshake.
+                        if envl.sent_early {
+                            me.timer = me.timeouts.first_byte.map(Delay::new);
+                            me.state = State::Reading;
+                        } else {
+                            me.timer = me.timeouts.write.map(Delay::new);
+                            me.state = State::Writing(0);
+                        }
+
+                        me.chan.replace(envl);
                     }
                 },
-            }
-        };
 
-        if let Ok(()) = self.shutdown.try_recv() {
-            return Poll::Ready(Ok(()));
-        }
+                State::Writing(written) => {
+                    let data = &me.chan.as_ref().expect("request in slot").data;
+
+                    match Pin::new(&mut me.io).poll_write(cx, &data[written..]) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return me.fail(e),
+                        Poll::Ready(Ok(0)) => {
+                            return me.fail(io::Error::from(io::ErrorKind::WriteZero));
+                        }
+                        Poll::Ready(Ok(wrlen)) if written + wrlen == data.len() => {
+                            me.state = State::Flushing
+                        }
+                        Poll::Ready(Ok(wrlen)) => me.state = State::Writing(written + wrlen),
+                    }
+                }
 
-        for _ in 0..8 {
-            let _ = Pin::new(&mut self.io).poll_write(cx, &envl.data);
-            let _ = Pin::new(&mut self.io).poll_flush(cx);
+                State::Flushing => match Pin::new(&mut me.io).poll_flush(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return me.fail(e),
+                    Poll::Ready(Ok(())) => {
+                        me.timer = me.timeouts.first_byte.map(Delay::new);
+                        me.state = State::Reading;
+                    }
+                },
 
-            let mut buf: [u8; 16800] = [0; 16800];
-            println!("Reading!");
-            match Pin::new(&mut self.io).poll_read(cx, &mut buf) {
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Ready(Ok(size)) => {
-                    println!("read ready!");
+                State::Reading => {
+                    let mut buf: [u8; 16800] = [0; 16800];
 
-                    if let Err(e) = self.decoder.decode(&buf[0..size]) {
-                        let err = io::Error::new(io::ErrorKind::Other, e);
+                    let size = match Pin::new(&mut me.io).poll_read(cx, &mut buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return me.fail(e),
+                        Poll::Ready(Ok(0)) => {
+                            return me.fail(io::Error::from(io::ErrorKind::UnexpectedEof));
+                        }
+                        Poll::Ready(Ok(size)) => size,
+                    };
 
-                        let _ = envl.chan_fn(|ch| ch.send(Err(err)));
+                    // The first byte is in, only the total deadline applies now.
+                    me.timer = None;
+                    me.answering = true;
+
+                    if let Err(e) = me.decoder.decode(&buf[0..size]) {
+                        return me.fail(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+
+                    if !me.decoder.finished() {
+                        continue;
                     }
 
-                    if self.decoder.finished() {
-                        let resp = self
-                            .decoder
-                            .get_resp()
-                            .expect("there should always be a response in slot");
+                    let mut resp = me
+                        .decoder
+                        .get_resp()
+                        .expect("there should always be a response in slot");
 
-                        match resp.status() {
-                            _ => {} // todo:
+                    resp.set_connection(Arc::clone(&me.info));
+
+                    let close = resp
+                        .headers()
+                        .contains(&Header::Connection(ConnectionState::Close));
+
+                    let envl = me.chan.as_mut().expect("request in slot");
+
+                    // Accepted as early data, but the server wants it after the handshake,
+                    // which is done by now, RFC 8470.
+                    if resp.code() == 425 && envl.sent_early {
+                        envl.sent_early = false;
+                        me.decoder = DataDecoder::new();
+                        me.answering = false;
+
+                        if !close {
+                            me.timer = me.timeouts.write.map(Delay::new);
+                            me.state = State::Writing(0);
+                            continue;
                         }
 
-                        let _ = envl.chan_fn(|ch| ch.send(Ok(resp)));
+                        // Left to a new connection.
+                        let mut envl = me.chan.take().expect("request in slot");
+                        let err = Stale::wrap(io::Error::other("425 Too Early"));
+                        let _ = envl.chan_fn(|ch| ch.send(Err(err)));
+
+                        me.shared.close();
+                        me.close();
+                        continue;
+                    }
+
+                    // The pool must see the connection as idle, or closed,
+                    // before the caller can send its next request.
+                    if close {
+                        me.shared.close();
+                    }
+
+                    me.decoder = DataDecoder::new();
+                    me.total = None;
+                    me.served += 1;
+                    me.answering = false;
+                    me.state = State::Idle;
+                    me.shared.done();
+
+                    let mut envl = me.chan.take().expect("request in slot");
+                    let _ = envl.chan_fn(|ch| ch.send(Ok(resp)));
+
+                    if close {
+                        me.close();
                     }
-                    // let _ = dbg!(channel.send(Response::dummy()));
                 }
 
-                _ => {}
+                State::Closing => {
+                    me.shared.close();
+
+                    return Pin::new(&mut me.io).poll_shutdown(cx);
+                }
             }
         }
+    }
+}
 
-        self.chan.replace(envl);
+impl<IO> Drop for HttpsConn<IO> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// What's needed to send a request again on a new connection.
+struct Retry {
+    pool: Arc<Pool>,
+    url: Url,
+    connector: Connector,
+    data: Vec<u8>,
+    deadline: Option<Instant>,
+
+    /// Whether the request may be sent again once written.
+    replayable: bool,
+}
 
-        Poll::Pending
+impl Retry {
+    fn send(self) -> oneshot::Receiver<io::Result<Response>> {
+        let (s, r) = oneshot::channel();
+        let envl = Envelope {
+            data: self.data,
+            oneshot: Some(s),
+            deadline: self.deadline,
+            // Not as early data again, it might be what failed the first time.
+            replayable: false,
+            sent_early: false,
+        };
+
+        self.pool.send(&self.url, envl, &self.connector);
+
+        r
+    }
+}
+
+/// Response to a request of `Client::execute`, failing with `Phase::Total`
+/// once the total deadline passed, be it while queued, connecting or being answered.
+///
+/// An idempotent request is sent once more if the keep-alive connection
+/// it went out on turns out to have been closed by the server,
+/// any request is if that connection closed while it was still queued.
+pub struct ResponseFuture {
+    recv: oneshot::Receiver<io::Result<Response>>,
+    total: Option<Delay>,
+    retry: Option<Retry>,
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<io::Result<Response>, oneshot::Canceled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = &mut *self;
+
+        loop {
+            match Pin::new(&mut me.recv).poll(cx) {
+                Poll::Ready(Ok(Err(e))) => match Stale::unwrap(e) {
+                    Ok((e, written)) => match me.retry.take() {
+                        Some(retry) if retry.replayable || !written => me.recv = retry.send(),
+                        _ => return Poll::Ready(Ok(Err(e))),
+                    },
+                    Err(e) => return Poll::Ready(Ok(Err(e))),
+                },
+                Poll::Ready(res) => return Poll::Ready(res),
+                Poll::Pending => break,
+            }
+        }
+
+        match timer::expired(&mut me.total, cx) {
+            true => Poll::Ready(Ok(Err(timer::elapsed(Phase::Total)))),
+            false => Poll::Pending,
+        }
     }
 }
 
 pub struct Client<'c> {
+    url: Url,
     user_agent: &'static str,
     headers: Option<HeaderList<'c>>,
-    shutdown: mpsc::Sender<()>,
-    sender: mpsc::Sender<Envelope>,
+    connector: Connector,
+    pool: Arc<Pool>,
 }
 
 impl<'c> Client<'c> {
     pub async fn connect(
-        url: &'static str,
+        url: &Url,
         user_agent: &'static str,
         headers: Option<&'c HashMap<&'c str, String>>,
     ) -> io::Result<Client<'c>> {
-        let io = TlsClient::create(None, url)?.await?;
+        let mut builder = ClientBuilder::new(user_agent);
 
-        let hdr = match headers {
-            None => None,
-            Some(map) => {
-                let mut hdrlist = HeaderList::new();
-                map.into_iter()
-                    .for_each(|(key, val)| hdrlist.put((key, &*val)));
-
-                Some(hdrlist)
-            }
-        };
-
-        let (sender, recv) = mpsc::channel();
+        if let Some(map) = headers {
+            builder.headers(map);
+        }
 
-        let (sender1, recv1) = mpsc::channel();
+        builder.connect(url).await
+    }
 
-        let conn = HttpsConn {
-            io,
-            recv,
-            chan: None,
-            state: State,
-            decoder: DataDecoder::new(),
-            shutdown: recv1,
-        };
+    /// Like `connect`, the only connection running over `io`, see `ClientBuilder::connect_over`.
+    pub async fn connect_over<IO>(
+        url: &Url,
+        user_agent: &'static str,
+        headers: Option<&'c HashMap<&'c str, String>>,
+        io: IO,
+    ) -> io::Result<Client<'c>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut builder = ClientBuilder::new(user_agent);
 
-        use lamp::Executor;
-        let _ = Executor::spawn(conn);
+        if let Some(map) = headers {
+            builder.headers(map);
+        }
 
-        Ok(Client {
-            user_agent,
-            headers: hdr,
-            shutdown: sender1,
-            sender,
-        })
+        builder.connect_over(url, io).await
     }
 
-    pub fn execute(&mut self, req: ReqBuilder) -> oneshot::Receiver<io::Result<Response>> {
+    pub fn execute(&mut self, req: ReqBuilder) -> ResponseFuture {
         let (s, r) = oneshot::channel();
+        let total = self.connector.timeouts().total;
+
+        let url = req.url().unwrap_or(&self.url).clone();
+        let replayable = req.method().is_replayable();
+        let data = req.construct_for(&self.url);
+        let deadline = total.map(|t| Instant::now() + t);
+
+        let retry = Some(Retry {
+            pool: Arc::clone(&self.pool),
+            url: url.clone(),
+            connector: self.connector.clone(),
+            data: data.clone(),
+            deadline,
+            replayable,
+        });
 
-        let data = req.construct();
         let envl = Envelope {
             data,
             oneshot: Some(s),
+            deadline,
+            replayable,
+            sent_early: false,
         };
 
-        // handle this later
-        let _res = self.sender.send(envl);
+        self.pool.send(&url, envl, &self.connector);
 
-        r
+        ResponseFuture {
+            recv: r,
+            total: total.map(Delay::new),
+            retry,
+        }
     }
 
-    pub fn shutdown(&self) -> Result<(), mpsc::SendError<()>> {
-        self.shutdown.send(())
+    /// Shuts down every connection of the client's pool.
+    pub fn shutdown(&self) {
+        self.pool.shutdown()
     }
 
     pub(crate) fn get_header_slice(&self) -> Option<&[(&'c str, &'c str)]> {
@@ -232,3 +705,644 @@ impl<'c> Client<'c> {
     //     RequestFuture::new(data, self)
     // }
 }
+
+pub struct ClientBuilder<'b> {
+    user_agent: &'static str,
+    headers: Option<&'b HashMap<&'b str, String>>,
+    resolver: Arc<dyn Resolve>,
+    attempt_delay: Option<Duration>,
+    timeouts: Timeouts,
+    pool: Option<Arc<Pool>>,
+    proxy: Option<Url>,
+    tls: TlsPolicy,
+    overrides: Overrides,
+    socket: SocketOptions,
+    proxy_header: Option<ProxyHeader>,
+    dialer: Option<Dialer>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+}
+
+impl<'b> ClientBuilder<'b> {
+    pub fn new(user_agent: &'static str) -> Self {
+        Self {
+            user_agent,
+            headers: None,
+            resolver: dns::default_resolver(),
+            attempt_delay: None,
+            timeouts: Timeouts::default(),
+            pool: None,
+            proxy: None,
+            tls: TlsPolicy::default(),
+            overrides: Overrides::default(),
+            socket: SocketOptions::default(),
+            proxy_header: None,
+            dialer: None,
+            #[cfg(unix)]
+            unix_socket: None,
+        }
+    }
+
+    pub fn headers(&mut self, headers: &'b HashMap<&'b str, String>) -> &mut Self {
+        self.headers.replace(headers);
+        self
+    }
+
+    /// Sets the resolver used to look up hosts,
+    /// by default a `CachingResolver` honouring `/etc/hosts` shared by every client.
+    pub fn resolver(&mut self, resolver: impl Resolve + 'static) -> &mut Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Opens every connection over a stream `dialer` connected instead of dialling TCP,
+    /// like an in-process pipe, a tunnel or a forwarded channel.
+    /// For `https` urls the handshake runs over it with the TLS settings of the host.
+    ///
+    /// Proxies, overrides of the target and the Unix socket don't apply,
+    /// the server name and the PROXY protocol header do.
+    pub fn dialer(&mut self, dialer: impl Dial + 'static) -> &mut Self {
+        self.dialer.replace(Dialer::new(dialer));
+        self
+    }
+
+    /// Connects to `addrs` instead of resolving `host` when going to `port`,
+    /// like `curl --resolve`. The url still gives the `Host` header,
+    /// the SNI and the name the certificate is checked against.
+    pub fn resolve(
+        &mut self,
+        host: &str,
+        port: u16,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<&mut Self, UrlError> {
+        self.overrides.add_addrs(host, port, addrs)?;
+        Ok(self)
+    }
+
+    /// Connects to `target` on `target_port` instead of `host` on `port`,
+    /// like `curl --connect-to`, everything else still going by the url.
+    ///
+    /// `target` is resolved as usual, or with the addresses given to `resolve`.
+    /// Through a proxy, it's the target the proxy is asked to connect to.
+    pub fn connect_to(
+        &mut self,
+        host: &str,
+        port: u16,
+        target: &str,
+        target_port: u16,
+    ) -> Result<&mut Self, UrlError> {
+        self.overrides
+            .add_connect_to(host, port, target, target_port)?;
+        Ok(self)
+    }
+
+    /// Sends `server_name` as SNI in handshakes with `host`, instead of `host` itself.
+    /// The server's certificate must then be valid for `server_name`.
+    pub fn server_name(&mut self, host: &str, server_name: &str) -> Result<&mut Self, UrlError> {
+        self.overrides.add_server_name(host, server_name)?;
+        Ok(self)
+    }
+
+    /// Sets the options of the TCP sockets connections are made from,
+    /// like the local address, `TCP_NODELAY` or keepalive.
+    /// An attempt whose socket can't be set up fails like a refused connect.
+    pub fn socket_options(&mut self, options: SocketOptions) -> &mut Self {
+        self.socket = options;
+        self
+    }
+
+    /// Sets how long a connection attempt may run before the next address
+    /// is raced against it, 250 milliseconds by default.
+    pub fn attempt_delay(&mut self, delay: Duration) -> &mut Self {
+        self.attempt_delay.replace(delay);
+        self
+    }
+
+    /// Sets the deadlines of connecting and of every request,
+    /// a phase running past its deadline fails with `io::ErrorKind::TimedOut`.
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Shares a connection pool between several clients,
+    /// by default every client gets its own.
+    pub fn pool(&mut self, pool: Arc<Pool>) -> &mut Self {
+        self.pool.replace(pool);
+        self
+    }
+
+    /// Sets the settings of TLS handshakes, like the trusted roots or the client certificate
+    /// presented to servers asking for one.
+    ///
+    /// With a policy set, these apply to the hosts none of its patterns match.
+    pub fn tls(&mut self, tls: TlsConfig) -> &mut Self {
+        self.tls.set_default(tls);
+        self
+    }
+
+    /// Picks the settings of TLS handshakes by host, see `TlsPolicy`.
+    pub fn tls_policy(&mut self, policy: TlsPolicy) -> &mut Self {
+        self.tls = policy;
+        self
+    }
+
+    /// Tunnels every connection through the proxy at `proxy`,
+    /// for both `http` and `https` urls.
+    ///
+    /// A `http://` proxy is asked for a tunnel with `CONNECT`,
+    /// a `socks5://` proxy is given the address we resolved,
+    /// a `socks5h://` proxy resolves the host itself.
+    /// Credentials in the proxy url are used to authenticate to SOCKS proxies.
+    pub fn proxy(&mut self, proxy: &Url) -> &mut Self {
+        self.proxy.replace(proxy.clone());
+        self
+    }
+
+    /// Sends `header` first on every connection, before the TLS handshake,
+    /// for servers behind a load balancer speaking the HAProxy PROXY protocol.
+    /// Connections over a Unix socket go without it.
+    ///
+    /// The header is part of the connection, so a pooled connection only serves
+    /// clients sending the same one: when relaying for several downstream clients,
+    /// give each its own header and its connections won't be shared with the others.
+    pub fn proxy_header(&mut self, header: ProxyHeader) -> &mut Self {
+        self.proxy_header.replace(header);
+        self
+    }
+
+    /// Sends every request over the Unix domain socket at `path`,
+    /// like `curl --unix-socket`, the url only giving the path and `Host` header.
+    ///
+    /// Only `http` urls can be used, there is no TLS over the socket.
+    #[cfg(unix)]
+    pub fn unix_socket(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.unix_socket.replace(path.into());
+        self
+    }
+
+    /// Creates the client and eagerly opens a first connection to `url`,
+    /// unless the pool already holds one.
+    pub async fn connect(&self, url: &Url) -> io::Result<Client<'b>> {
+        let connector = self.connector();
+        let pool = self.shared_pool();
+
+        if pool.connections_for(url, &connector) == 0 {
+            let io = connector.connect(url, None).await?;
+            pool.insert(url, io, &connector);
+        }
+
+        Ok(self.client(url, connector, pool))
+    }
+
+    /// Creates the client with a single connection to `url` running over `io`,
+    /// a stream the caller already connected, like an in-process pipe or a forwarded channel.
+    /// For `https` urls the handshake runs over it with the TLS settings of the host.
+    ///
+    /// No other connection is ever opened: once `io` is closed, requests fail
+    /// with `NotConnected`. Use `dialer` for a client that opens new streams as needed.
+    pub async fn connect_over<IO>(&self, url: &Url, io: IO) -> io::Result<Client<'b>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut connector = self.connector();
+        connector.set_dialer(Some(Dialer::once(io)));
+
+        let pool = self.shared_pool();
+
+        let io = connector.connect(url, None).await?;
+        pool.insert(url, io, &connector);
+
+        Ok(self.client(url, connector, pool))
+    }
+
+    fn connector(&self) -> Connector {
+        let mut connector = Connector::new(Arc::clone(&self.resolver));
+
+        if let Some(delay) = self.attempt_delay {
+            connector.set_attempt_delay(delay);
+        }
+
+        connector
+            .set_timeouts(self.timeouts)
+            .set_proxy(self.proxy.clone())
+            .set_tls(self.tls.clone())
+            .set_overrides(self.overrides.clone())
+            .set_socket_options(self.socket.clone())
+            .set_proxy_header(self.proxy_header.clone())
+            .set_dialer(self.dialer.clone());
+
+        #[cfg(unix)]
+        connector.set_unix_socket(self.unix_socket.clone());
+
+        connector
+    }
+
+    fn shared_pool(&self) -> Arc<Pool> {
+        match self.pool {
+            Some(ref pool) => Arc::clone(pool),
+            None => Arc::new(Pool::new()),
+        }
+    }
+
+    fn client(&self, url: &Url, connector: Connector, pool: Arc<Pool>) -> Client<'b> {
+        let hdr = match self.headers {
+            None => None,
+            Some(map) => {
+                let mut hdrlist = HeaderList::new();
+                map.iter().for_each(|(key, val)| hdrlist.put((key, val)));
+
+                Some(hdrlist)
+            }
+        };
+
+        Client {
+            url: url.clone(),
+            user_agent: self.user_agent,
+            headers: hdr,
+            connector,
+            pool,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ClientBuilder, Envelope, HttpsConn, Method};
+    use crate::connect::Connector;
+    use crate::dns;
+    use crate::http1::pool::{ConnState, Pool};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{
+        OK, USER_AGENT, ca_config, listen, run, serve, server_config, tls_server,
+    };
+    use crate::timer::{Elapsed, Phase, Timeouts};
+    use crate::url::Url;
+    use futures::channel::{mpsc, oneshot};
+    use lamp::runtime::Executor;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn connect_to_local_http_server() {
+        let (port, server) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"GET /health HTTP/1.1\r\n"));
+
+            sock.write_all(OK).unwrap();
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/health", port)).unwrap();
+
+            let mut client = Client::connect(&url, USER_AGENT, None).await.unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+            assert_eq!(resp.content(), Some("ok".as_bytes()));
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stale_connection_retried() {
+        let (port, server) = listen(|listener| {
+            let mut buf = [0u8; 1024];
+
+            // The first connection closes as soon as the second request arrives.
+            let (mut sock, _) = listener.accept().unwrap();
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(OK).unwrap();
+            let _ = sock.read(&mut buf).unwrap();
+            drop(sock);
+
+            let (mut sock, _) = listener.accept().unwrap();
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"GET / HTTP/1.1\r\n"));
+            sock.write_all(OK).unwrap();
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = Client::connect(&url, USER_AGENT, None).await.unwrap();
+
+            for _ in 0..2 {
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+            }
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn queued_request_sent_again() {
+        let (port, server) = listen(|listener| {
+            let mut buf = [0u8; 1024];
+
+            // Answers a first request, then closes on the second one,
+            // the POST behind it never reaching the server.
+            let (mut sock, _) = listener.accept().unwrap();
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(OK).unwrap();
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"GET / HTTP/1.1\r\n"));
+            drop(sock);
+
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut methods = Vec::new();
+
+            for _ in 0..2 {
+                let len = sock.read(&mut buf).unwrap();
+                methods.push(buf[..len].starts_with(b"POST ").then_some("POST"));
+                sock.write_all(OK).unwrap();
+            }
+
+            assert!(
+                methods.contains(&Some("POST")),
+                "the POST was not sent again"
+            );
+        });
+
+        let mut pool = Pool::new();
+        pool.set_max_per_host(1);
+        let pool = Arc::new(pool);
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .pool(pool)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client.execute(ReqBuilder::new(Method::GET)).await;
+            assert_eq!(resp.unwrap().unwrap().code(), 200);
+
+            let get = client.execute(ReqBuilder::new(Method::GET));
+            let post = client.execute(ReqBuilder::new(Method::POST));
+
+            let (get, post) = futures::future::join(get, post).await;
+            assert_eq!(get.unwrap().unwrap().code(), 200);
+            assert_eq!(post.unwrap().unwrap().code(), 200);
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn data_while_idle_fails_connection() {
+        let (port, server) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(OK).unwrap();
+
+            thread::sleep(Duration::from_millis(50));
+            sock.write_all(OK).unwrap();
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+            let connector = Connector::new(dns::default_resolver());
+            let io = connector.connect(&url, None).await.unwrap();
+            let info = io.info();
+
+            let (send, recv) = mpsc::unbounded();
+            let (_shutdown, shutdown) = oneshot::channel();
+            let state = Arc::new(ConnState::new());
+            let timeouts = connector.timeouts();
+            let idle = Duration::from_secs(5);
+
+            let (s, r) = oneshot::channel();
+
+            let envl = Envelope {
+                data: b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n".to_vec(),
+                oneshot: Some(s),
+                deadline: None,
+                replayable: true,
+                sent_early: false,
+            };
+
+            send.unbounded_send(envl).unwrap();
+
+            let conn = HttpsConn::new(io, recv, shutdown, state, timeouts, idle, info);
+            let (done, closed) = oneshot::channel();
+
+            Executor::spawn(async move {
+                let _ = done.send(conn.await);
+            });
+
+            assert_eq!(r.await.unwrap().unwrap().code(), 200);
+
+            let err = closed.await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        // Accepts the connection but never answers the ClientHello.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let timeouts = Timeouts {
+                handshake: Some(Duration::from_millis(100)),
+                ..Timeouts::default()
+            };
+
+            let err = match ClientBuilder::new(USER_AGENT)
+                .timeouts(timeouts)
+                .connect(&url)
+                .await
+            {
+                Ok(_) => panic!("handshake with a silent server succeeded"),
+                Err(e) => e,
+            };
+
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let phase = err.get_ref().and_then(|e| e.downcast_ref::<Elapsed>());
+            assert_eq!(phase.map(Elapsed::phase), Some(Phase::Handshake));
+        });
+
+        drop(listener);
+    }
+
+    #[test]
+    fn first_byte_timeout() {
+        // Reads the request and never answers it.
+        let (port, server) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf);
+            let _ = sock.read(&mut buf);
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+
+            let timeouts = Timeouts {
+                first_byte: Some(Duration::from_millis(100)),
+                ..Timeouts::default()
+            };
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .timeouts(timeouts)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let err = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let elapsed = err.get_ref().unwrap().downcast_ref::<Elapsed>();
+            assert_eq!(elapsed.unwrap().phase(), Phase::FirstByte);
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn total_timeout_while_connecting() {
+        let (port, server) = tls_server(false);
+
+        // Connects, as the backlog accepts for it, and never answers the handshake.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("https://127.0.0.1:{}/", silent.local_addr().unwrap().port());
+
+        run(async move {
+            let url = Url::parse(&format!("https://localhost:{}/", port)).unwrap();
+            let silent_url = Url::parse(&silent_url).unwrap();
+
+            let timeouts = Timeouts {
+                total: Some(Duration::from_millis(200)),
+                ..Timeouts::default()
+            };
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(ca_config())
+                .timeouts(timeouts)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let mut req = ReqBuilder::new(Method::GET);
+            req.set_url(&silent_url);
+
+            let err = client.execute(req).await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let elapsed = err.get_ref().unwrap().downcast_ref::<Elapsed>();
+            assert_eq!(elapsed.unwrap().phase(), Phase::Total);
+
+            let resp = client.execute(ReqBuilder::new(Method::GET)).await;
+            assert_eq!(resp.unwrap().unwrap().code(), 200);
+
+            drop(silent);
+        });
+
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn too_early_sent_again() {
+        let mut config = server_config(false);
+        config.max_early_data_size = 16384;
+        let config = Arc::new(config);
+
+        // Answers the request of the resumed connection, sent as early data,
+        // with 425 and returns it along with the one sent again.
+        let (port, server) = listen(move |listener| -> io::Result<(Vec<u8>, Vec<u8>)> {
+            for too_early in [false, true] {
+                let (mut sock, _) = listener.accept()?;
+                let mut conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+                let mut early = Vec::new();
+
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut sock)?;
+                }
+
+                if let Some(mut reader) = conn.early_data() {
+                    reader.read_to_end(&mut early)?;
+                }
+
+                let mut tls = rustls::StreamOwned::new(conn, sock);
+                let mut buf = [0u8; 1024];
+                let mut len = 0;
+
+                if too_early {
+                    tls.write_all(b"HTTP/1.1 425 Too Early\r\nContent-Length: 2\r\n\r\nno")?;
+                    len = tls.read(&mut buf)?;
+                } else {
+                    let _ = tls.read(&mut buf)?;
+                }
+
+                tls.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                )?;
+                tls.conn.send_close_notify();
+                tls.flush()?;
+
+                if too_early {
+                    return Ok((early, buf[..len].to_vec()));
+                }
+            }
+
+            unreachable!()
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut tls = ca_config();
+            tls.set_early_data(true);
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            for _ in 0..2 {
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+            }
+
+            let (early, again) = server.join().unwrap().unwrap();
+            assert!(early.starts_with(b"GET / HTTP/1.1\r\n"));
+            assert_eq!(early, again);
+        });
+    }
+}
@@ -1,5 +1,6 @@
 pub mod client;
 pub mod headers;
 pub(crate) mod poll_channels;
+pub mod pool;
 pub mod request;
 pub mod response;
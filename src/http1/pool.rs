@@ -0,0 +1,525 @@
+use super::client::{Envelope, HttpsConn};
+use crate::connect::{Connector, Route, Transport};
+use crate::url::{Host, Url};
+use futures::channel::{mpsc, oneshot};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_PER_HOST: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Key of the pool, connections are only shared within one origin
+/// and between clients connecting to it the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Origin {
+    scheme: String,
+    host: Host,
+    port: u16,
+    route: Route,
+}
+
+impl Origin {
+    pub(crate) fn new(url: &Url, connector: &Connector) -> Self {
+        Self {
+            scheme: url.scheme().to_string(),
+            host: url.host().clone(),
+            port: url.port_or_default().unwrap_or(0),
+            route: connector.route(url),
+        }
+    }
+
+    fn is_of(&self, url: &Url) -> bool {
+        self.scheme == url.scheme()
+            && self.host == *url.host()
+            && self.port == url.port_or_default().unwrap_or(0)
+    }
+}
+
+/// State of a connection shared between its task and the pool.
+#[derive(Debug)]
+pub(crate) struct ConnState {
+    /// Requests sent to the connection and not answered yet.
+    pending: AtomicUsize,
+
+    /// Set once the connection task has finished.
+    closed: AtomicBool,
+
+    /// Moment the connection last became idle.
+    idle_since: Mutex<Instant>,
+}
+
+impl ConnState {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            idle_since: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Marks one request as answered.
+    pub(crate) fn done(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            *self.idle_since.lock().unwrap() = Instant::now();
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending.load(Ordering::Acquire) == 0
+    }
+
+    fn is_expired(&self, timeout: Duration) -> bool {
+        self.is_idle() && self.idle_since.lock().unwrap().elapsed() >= timeout
+    }
+}
+
+/// The pool's end of a connection task.
+struct Handle {
+    sender: mpsc::UnboundedSender<Envelope>,
+    shutdown: Option<oneshot::Sender<()>>,
+    state: Arc<ConnState>,
+}
+
+impl Handle {
+    /// Creates a handle along with the receiving ends for the connection task.
+    fn new() -> (
+        Self,
+        mpsc::UnboundedReceiver<Envelope>,
+        oneshot::Receiver<()>,
+    ) {
+        let (sender, recv) = mpsc::unbounded();
+        let (shutdown, shutdown_recv) = oneshot::channel();
+
+        let handle = Self {
+            sender,
+            shutdown: Some(shutdown),
+            state: Arc::new(ConnState::new()),
+        };
+
+        (handle, recv, shutdown_recv)
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(chan) = self.shutdown.take() {
+            let _ = chan.send(());
+        }
+    }
+}
+
+/// Pool of keep-alive HTTP/1 connections, keyed by scheme, host and port.
+///
+/// Requests go to an idle connection of their origin when there is one,
+/// otherwise a new connection is opened as long as the origin is under
+/// `max_per_host`, otherwise they are queued on the least busy connection.
+/// Connections idle for longer than `idle_timeout` close themselves.
+///
+/// A pool can be shared by clients with different settings,
+/// connections are then only reused by clients connecting the same way,
+/// through the same proxy, to the same address, with the same TLS config
+/// and the same PROXY protocol header.
+pub struct Pool {
+    conns: Mutex<HashMap<Origin, Vec<Handle>>>,
+    max_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Self {
+            conns: Mutex::new(HashMap::new()),
+            max_per_host: DEFAULT_MAX_PER_HOST,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    pub fn set_max_per_host(&mut self, max: usize) -> &mut Self {
+        self.max_per_host = max.max(1);
+        self
+    }
+
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Shuts down every connection that has been idle for too long.
+    pub fn evict_idle(&self) {
+        let mut conns = self.conns.lock().unwrap();
+
+        conns.values_mut().for_each(|list| self.evict(list));
+        conns.retain(|_, list| !list.is_empty());
+    }
+
+    /// Shuts down every connection of the pool.
+    pub fn shutdown(&self) {
+        let mut conns = self.conns.lock().unwrap();
+
+        conns
+            .drain()
+            .flat_map(|(_, list)| list)
+            .for_each(|mut handle| handle.shutdown());
+    }
+
+    /// Amount of open connections to the origin of `url`, whatever client opened them.
+    pub fn connections(&self, url: &Url) -> usize {
+        let mut conns = self.conns.lock().unwrap();
+
+        conns
+            .iter_mut()
+            .filter(|(origin, _)| origin.is_of(url))
+            .map(|(_, list)| {
+                self.evict(list);
+                list.len()
+            })
+            .sum()
+    }
+
+    /// Amount of open connections to the origin of `url` that `connector` may reuse.
+    pub(crate) fn connections_for(&self, url: &Url, connector: &Connector) -> usize {
+        let mut conns = self.conns.lock().unwrap();
+
+        conns
+            .get_mut(&Origin::new(url, connector))
+            .map_or(0, |list| {
+                self.evict(list);
+                list.len()
+            })
+    }
+
+    /// Adds a connection `connector` established to the pool.
+    pub(crate) fn insert(&self, url: &Url, io: Transport, connector: &Connector) {
+        use lamp::Executor;
+
+        let (handle, recv, shutdown) = Handle::new();
+        let state = Arc::clone(&handle.state);
+        let info = io.info();
+        let timeouts = connector.timeouts();
+        let conn = HttpsConn::new(io, recv, shutdown, state, timeouts, self.idle_timeout, info);
+
+        Executor::spawn(conn);
+
+        let mut conns = self.conns.lock().unwrap();
+        conns
+            .entry(Origin::new(url, connector))
+            .or_default()
+            .push(handle);
+    }
+
+    /// Sends a request to a connection of the origin of `url`,
+    /// opening a new one with `connector` if needed.
+    pub(crate) fn send(&self, url: &Url, mut envl: Envelope, connector: &Connector) {
+        let mut conns = self.conns.lock().unwrap();
+        let list = conns.entry(Origin::new(url, connector)).or_default();
+
+        self.evict(list);
+
+        loop {
+            let index = match self.pick(list) {
+                Some(index) => index,
+                None => {
+                    let early_data = match connector.sends_early_data(url) {
+                        true => envl.early_data(),
+                        false => None,
+                    };
+
+                    list.push(self.open(url, connector, early_data));
+                    list.len() - 1
+                }
+            };
+
+            let handle = &list[index];
+            handle.state.pending.fetch_add(1, Ordering::AcqRel);
+
+            // The connection might have closed since we last looked at it.
+            match handle.sender.unbounded_send(envl) {
+                Ok(()) => return,
+                Err(e) => {
+                    handle.state.close();
+                    envl = e.into_inner();
+                    envl.not_sent_early();
+                    list.remove(index);
+                }
+            }
+        }
+    }
+
+    /// Picks the connection a new request should go to,
+    /// `None` means a new connection should be opened.
+    fn pick(&self, list: &[Handle]) -> Option<usize> {
+        if let Some(index) = list.iter().position(|h| h.state.is_idle()) {
+            return Some(index);
+        }
+
+        if list.len() < self.max_per_host {
+            return None;
+        }
+
+        list.iter()
+            .enumerate()
+            .min_by_key(|(_, h)| h.state.pending.load(Ordering::Acquire))
+            .map(|(index, _)| index)
+    }
+
+    fn evict(&self, list: &mut Vec<Handle>) {
+        list.retain_mut(|handle| {
+            if handle.state.is_closed() {
+                return false;
+            }
+
+            if handle.state.is_expired(self.idle_timeout) {
+                handle.shutdown();
+                return false;
+            }
+
+            true
+        });
+    }
+
+    /// Spawns a task connecting to `url` and then serving requests,
+    /// the first one being sent as `early_data` if there is some.
+    fn open(&self, url: &Url, connector: &Connector, early_data: Option<Vec<u8>>) -> Handle {
+        use lamp::Executor;
+
+        let (handle, mut recv, shutdown) = Handle::new();
+        let idle_timeout = self.idle_timeout;
+
+        let state = Arc::clone(&handle.state);
+        let connector = connector.clone();
+        let url = url.clone();
+
+        Executor::spawn(async move {
+            match connector.connect(&url, early_data).await {
+                Ok(io) => {
+                    let timeouts = connector.timeouts();
+                    let info = io.info();
+
+                    HttpsConn::new(io, recv, shutdown, state, timeouts, idle_timeout, info).await
+                }
+
+                Err(e) => {
+                    state.close();
+                    recv.close();
+
+                    // Everything queued so far learns why it won't be answered.
+                    while let Ok(mut envl) = recv.try_recv() {
+                        let err = io::Error::new(e.kind(), e.to_string());
+                        let _ = envl.chan_fn(|ch| ch.send(Err(err)));
+                    }
+
+                    Err(e)
+                }
+            }
+        });
+
+        handle
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handle, Origin, Pool};
+    use crate::connect::Connector;
+    use crate::dns;
+    use crate::http1::client::{Client, ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::proxy_header::{ProxyHeader, ProxyVersion};
+    use crate::sockopt::SocketOptions;
+    use crate::testing::{OK, USER_AGENT, listen, run, serve};
+    use crate::tls_config::{TlsConfig, TlsPolicy};
+    use crate::url::Url;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    fn handle(pending: usize) -> Handle {
+        let (handle, _recv, _shutdown) = Handle::new();
+        handle.state.pending.store(pending, Ordering::Release);
+
+        handle
+    }
+
+    #[test]
+    fn pick_prefers_idle() {
+        let pool = Pool::new();
+        let list = vec![handle(2), handle(0), handle(1)];
+
+        assert_eq!(pool.pick(&list), Some(1));
+    }
+
+    #[test]
+    fn pick_opens_under_limit() {
+        let mut pool = Pool::new();
+        pool.set_max_per_host(3);
+
+        let list = vec![handle(2), handle(1)];
+        assert_eq!(pool.pick(&list), None);
+
+        let list = vec![handle(2), handle(1), handle(4)];
+        assert_eq!(pool.pick(&list), Some(1));
+    }
+
+    #[test]
+    fn evict_idle_and_closed() {
+        let mut pool = Pool::new();
+        pool.set_idle_timeout(Duration::from_secs(30));
+
+        let expired = handle(0);
+        *expired.state.idle_since.lock().unwrap() = Instant::now() - Duration::from_secs(60);
+
+        let closed = handle(1);
+        closed.state.close();
+
+        let busy = handle(1);
+        *busy.state.idle_since.lock().unwrap() = Instant::now() - Duration::from_secs(60);
+
+        let mut list = vec![expired, closed, busy, handle(0)];
+        pool.evict(&mut list);
+
+        assert_eq!(list.len(), 2);
+        assert!(list.iter().all(|h| !h.state.is_closed()));
+    }
+
+    #[test]
+    fn origin_per_route() {
+        let url = Url::parse("https://example.test/").unwrap();
+        let direct = Connector::new(dns::default_resolver());
+
+        let mut proxied = direct.clone();
+        proxied.set_proxy(Some(Url::parse("http://proxy.test:3128").unwrap()));
+
+        let mut tls = TlsConfig::new();
+        tls.set_early_data(true);
+        let mut other_tls = direct.clone();
+        other_tls.set_tls(TlsPolicy::new(tls));
+
+        let mut socket = SocketOptions::new();
+        socket.set_interface("eth1");
+        let mut other_socket = direct.clone();
+        other_socket.set_socket_options(socket);
+
+        let header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "192.0.2.1:4000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        );
+        let mut with_header = direct.clone();
+        with_header.set_proxy_header(Some(header.clone()));
+        let mut same_header = direct.clone();
+        same_header.set_proxy_header(Some(header));
+
+        let origin = Origin::new(&url, &direct);
+        assert_eq!(origin, Origin::new(&url, &direct.clone()));
+        assert_ne!(origin, Origin::new(&url, &proxied));
+        assert_ne!(origin, Origin::new(&url, &other_tls));
+        assert_ne!(origin, Origin::new(&url, &other_socket));
+        assert_ne!(origin, Origin::new(&url, &with_header));
+        assert_eq!(
+            Origin::new(&url, &with_header),
+            Origin::new(&url, &same_header)
+        );
+        assert!(Origin::new(&url, &proxied).is_of(&url));
+    }
+
+    #[test]
+    fn reuse_pooled_connection() {
+        let (port, server) = listen(|listener| {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+
+            for _ in 0..2 {
+                let _ = sock.read(&mut buf).unwrap();
+                sock.write_all(OK).unwrap();
+            }
+
+            // Both requests must have gone through the first connection.
+            listener.set_nonblocking(true).unwrap();
+            assert!(listener.accept().is_err(), "a second connection was opened");
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = Client::connect(&url, USER_AGENT, None).await.unwrap();
+
+            for _ in 0..2 {
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+            }
+        });
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn idle_connection_closed() {
+        let (port, server) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+
+            let _ = sock.read(&mut buf).unwrap();
+            sock.write_all(OK).unwrap();
+            let answered = Instant::now();
+
+            sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(sock.read(&mut buf).unwrap(), 0);
+            assert!(answered.elapsed() >= Duration::from_millis(100));
+        });
+
+        // Kept alive, dropping the pool would close the connection right away.
+        let mut pool = Pool::new();
+        pool.set_idle_timeout(Duration::from_millis(100));
+        let pool = Arc::new(pool);
+        let shared = Arc::clone(&pool);
+
+        run(async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .pool(shared)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+
+            // The connection must be closed by its own task, still running.
+            server.join().unwrap();
+        });
+
+        drop(pool);
+    }
+}
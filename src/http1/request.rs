@@ -1,4 +1,5 @@
 use super::client::{Client, Method};
+use crate::url::Url;
 use std::mem::MaybeUninit;
 
 const HEADER_MAX: usize = 24;
@@ -34,13 +35,13 @@ impl<'h> HeaderList<'h> {
     }
 
     fn can_push(&self) -> bool {
-        self.cursor >= HEADER_MAX
+        self.cursor < HEADER_MAX
     }
 
     pub(crate) fn put(&mut self, hdr: (&'h str, &'h str)) {
         // Ensures we don't overflow.
         // This function should be coupled with `can_push`.
-        if self.can_push() {
+        if !self.can_push() {
             return;
         }
 
@@ -55,7 +56,7 @@ impl<'h> HeaderList<'h> {
             return &[];
         }
 
-        let slice = &self.hdr[0..self.cursor - 1];
+        let slice = &self.hdr[0..self.cursor];
         unsafe {
             &*(slice as *const [MaybeUninit<(&'h str, &'h str)>] as *const [(&'h str, &'h str)])
         }
@@ -95,7 +96,6 @@ impl<'i> Iterator for HdrListIter<'i> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.pos == self.hdrs.cursor {
-            println!("returning none");
             return None;
         }
 
@@ -125,6 +125,7 @@ impl std::ops::Drop for HeaderList<'_> {
 pub struct ReqBuilder<'b> {
     method: Method,
     route: Option<&'b str>,
+    url: Option<&'b Url>,
     headers: Option<&'b [(&'b str, &'b str)]>,
     extra_headers: Option<HeaderList<'b>>,
     content: Option<&'b [u8]>,
@@ -137,6 +138,7 @@ impl<'b> ReqBuilder<'b> {
         Self {
             method,
             route: None,
+            url: None,
             headers: None,
             extra_headers: None,
             content: None,
@@ -153,6 +155,21 @@ impl<'b> ReqBuilder<'b> {
         self
     }
 
+    /// Sets the target of the request from a `Url`,
+    /// its path and query become the route and its authority the `Host` header.
+    pub fn set_url(&mut self, url: &'b Url) -> &mut Self {
+        self.url.replace(url);
+        self
+    }
+
+    pub(crate) fn url(&self) -> Option<&'b Url> {
+        self.url
+    }
+
+    pub(crate) fn method(&self) -> Method {
+        self.method
+    }
+
     pub fn set_content(&mut self, content: &'b [u8]) -> &mut Self {
         self.content.replace(content);
         self
@@ -179,23 +196,52 @@ impl<'b> ReqBuilder<'b> {
     }
 
     pub(crate) fn construct(self) -> Vec<u8> {
+        self.build(None)
+    }
+
+    /// Constructs the request, targeting `url` unless one was set on the builder.
+    pub(crate) fn construct_for(self, url: &Url) -> Vec<u8> {
+        self.build(Some(url))
+    }
+
+    fn build(self, default_url: Option<&Url>) -> Vec<u8> {
         let mut req = Vec::with_capacity(8192);
+        let url = self.url.or(default_url);
 
         // First HTTP line.
         req.extend_from_slice(self.method.bytes());
 
-        match self.route {
-            None => {
-                req.push(b'/');
+        match (self.route, url) {
+            (Some(route), _) => {
+                req.extend_from_slice(route.as_bytes());
             }
 
-            Some(route) => {
-                req.extend_from_slice(route.as_bytes());
+            (None, Some(url)) => {
+                req.extend_from_slice(url.request_target().as_bytes());
+            }
+
+            (None, None) => {
+                req.push(b'/');
             }
         };
 
         req.extend_from_slice(" HTTP/1.1\r\n".as_bytes());
 
+        let host_set = self
+            .headers
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(self.extra_headers.iter().flat_map(|hdrs| hdrs.iter()))
+            .any(|(name, _)| name.eq_ignore_ascii_case("Host"));
+
+        // A `Host` header given by the caller wins over the one of the url.
+        if let (Some(url), false) = (url, host_set) {
+            req.extend_from_slice("Host: ".as_bytes());
+            req.extend_from_slice(url.authority().as_bytes());
+            req.extend_from_slice(Self::CRLF);
+        }
+
         // Headers
         match self.headers {
             None => {}
@@ -254,7 +300,7 @@ impl<'b> ReqBuilder<'b> {
 
 #[cfg(test)]
 mod tests {
-    use super::{HeaderList, Method, ReqBuilder};
+    use super::{HeaderList, Method, ReqBuilder, Url};
 
     #[test]
     fn create_header_list() {
@@ -305,4 +351,49 @@ mod tests {
 
         println!("{}", str_req.unwrap());
     }
+
+    #[test]
+    fn extra_headers_sent() {
+        let mut req = ReqBuilder::new(Method::GET);
+        req.add_headers([("Accept", "*/*"), ("X-Trace", "1")]);
+
+        let bytes = req.construct();
+        let str_req = std::str::from_utf8(&bytes).unwrap();
+
+        assert_eq!(
+            str_req,
+            "GET / HTTP/1.1\r\nAccept: */*\r\nX-Trace: 1\r\n\r\n"
+        );
+
+        let mut hdrs = HeaderList::new();
+        hdrs.put(("Accept", "*/*"));
+        hdrs.put(("X-Trace", "1"));
+        assert_eq!(hdrs.header_slice().len(), 2);
+    }
+
+    #[test]
+    fn caller_host_wins() {
+        let url = Url::parse("http://127.0.0.1:8080/").unwrap();
+
+        let mut req = ReqBuilder::new(Method::GET);
+        req.set_url(&url).add_headers([("host", "example.com")]);
+
+        let bytes = req.construct();
+        let str_req = std::str::from_utf8(&bytes).unwrap();
+
+        assert_eq!(str_req, "GET / HTTP/1.1\r\nhost: example.com\r\n\r\n");
+    }
+
+    #[test]
+    fn req_builder_url() {
+        let url = Url::parse("https://example.com:8443/a%20b?q=1#frag").unwrap();
+
+        let mut req = ReqBuilder::new(Method::GET);
+        req.set_url(&url);
+
+        let bytes = req.construct();
+        let str_req = std::str::from_utf8(&bytes).unwrap();
+
+        assert!(str_req.starts_with("GET /a%20b?q=1 HTTP/1.1\r\nHost: example.com:8443\r\n"));
+    }
 }
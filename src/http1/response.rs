@@ -1,8 +1,9 @@
 use crate::http1::headers::{self, ConnectionState, Header};
+use crate::info::ConnectionInfo;
 use memchr::memchr;
-use rustls_pki_types::SubjectPublicKeyInfoDer;
 use std::io::{BufRead, Cursor};
 use std::str;
+use std::sync::Arc;
 use std::task::Poll;
 
 const VEC_PREALLOC: usize = 16 * 1024;
@@ -325,6 +326,7 @@ impl DataDecoder {
             code: status_code,
             headers,
             content: None,
+            connection: None,
         };
 
         me.resp = Some(resp);
@@ -377,6 +379,9 @@ pub struct Response {
     code: u16,
     headers: Vec<Header>,
     content: Option<Vec<u8>>,
+
+    /// Connection the response came over.
+    connection: Option<Arc<ConnectionInfo>>,
 }
 
 impl Response {
@@ -385,9 +390,14 @@ impl Response {
             code: 100,
             headers: vec![],
             content: None,
+            connection: None,
         }
     }
 
+    pub(crate) fn set_connection(&mut self, info: Arc<ConnectionInfo>) {
+        self.connection.replace(info);
+    }
+
     pub fn code(&self) -> u16 {
         self.code
     }
@@ -400,6 +410,11 @@ impl Response {
         self.content.as_ref().map(|vec| &**vec)
     }
 
+    /// Addresses and TLS parameters of the connection the response came over.
+    pub fn connection(&self) -> Option<&ConnectionInfo> {
+        self.connection.as_deref()
+    }
+
     pub fn status(&self) -> ResponseType {
         use ResponseType::*;
 
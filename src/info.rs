@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+use rustls::{CipherSuite, ClientConnection, HandshakeKind, NamedGroup, ProtocolVersion};
+use rustls_pki_types::CertificateDer;
+
+use super::connect::Addrs;
+
+/// What the TLS handshake of a connection settled on.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    version: Option<ProtocolVersion>,
+    cipher_suite: Option<CipherSuite>,
+    key_exchange_group: Option<NamedGroup>,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Vec<CertificateDer<'static>>,
+    resumed: bool,
+    early_data_accepted: bool,
+}
+
+impl TlsInfo {
+    pub(crate) fn new(conn: &ClientConnection) -> Self {
+        Self {
+            version: conn.protocol_version(),
+            cipher_suite: conn.negotiated_cipher_suite().map(|suite| suite.suite()),
+            key_exchange_group: conn.negotiated_key_exchange_group().map(|kx| kx.name()),
+            alpn_protocol: conn.alpn_protocol().map(<[u8]>::to_vec),
+            peer_certificates: conn.peer_certificates().unwrap_or_default().to_vec(),
+            resumed: conn.handshake_kind() == Some(HandshakeKind::Resumed),
+            early_data_accepted: conn.is_early_data_accepted(),
+        }
+    }
+
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        self.cipher_suite
+    }
+
+    pub fn key_exchange_group(&self) -> Option<NamedGroup> {
+        self.key_exchange_group
+    }
+
+    /// Protocol agreed on with ALPN, `None` if the server didn't pick one.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    /// Chain the server presented, its own certificate first.
+    pub fn peer_certificates(&self) -> &[CertificateDer<'static>] {
+        &self.peer_certificates
+    }
+
+    /// Whether an earlier session was resumed instead of running a full handshake.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Whether the server processed the first request as 0-RTT early data.
+    /// When it didn't, the request was sent again after the handshake.
+    pub fn early_data_accepted(&self) -> bool {
+        self.early_data_accepted
+    }
+}
+
+/// Details of the connection a response came over.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    tls: Option<TlsInfo>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(addrs: Option<Addrs>, tls: Option<TlsInfo>) -> Self {
+        Self {
+            local_addr: addrs.and_then(|addrs| addrs.local),
+            peer_addr: addrs.map(|addrs| addrs.peer),
+            tls,
+        }
+    }
+
+    /// Address the connection was made from, `None` over a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Address the connection was made to, the proxy's when going through one,
+    /// `None` over a Unix socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// What the TLS handshake settled on, `None` for cleartext connections.
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+use rustls::KeyLog;
+
+/// Writes the secrets of TLS handshakes in the NSS key log format,
+/// which Wireshark reads to decrypt captured traffic.
+///
+/// Anyone reading the log can decrypt the connections, it's only meant for debugging.
+pub struct KeyLogWriter<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> KeyLogWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<W> fmt::Debug for KeyLogWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyLogWriter").finish_non_exhaustive()
+    }
+}
+
+/// One `label client_random secret` line, both in lowercase hex.
+fn line(label: &str, client_random: &[u8], secret: &[u8]) -> String {
+    let mut line =
+        String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+    line.push_str(label);
+
+    for bytes in [client_random, secret] {
+        line.push(' ');
+
+        for b in bytes {
+            line.push_str(&format!("{:02x}", b));
+        }
+    }
+
+    line.push('\n');
+    line
+}
+
+impl<W: Write + Send> KeyLog for KeyLogWriter<W> {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = line(label, client_random, secret);
+        let mut out = self.out.lock().unwrap();
+
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            log::warn!("couldn't write to the key log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyLogWriter;
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{USER_AGENT, ca_config, run, tls_server};
+    use crate::url::Url;
+    use rustls::KeyLog;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn nss_format() {
+        let log = KeyLogWriter::new(Vec::new());
+        log.log("CLIENT_RANDOM", &[0x00, 0xab], &[0x01, 0xff, 0x10]);
+        log.log("EXPORTER_SECRET", &[0x02], &[0x03]);
+
+        let out = log.out.into_inner().unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out, "CLIENT_RANDOM 00ab 01ff10\nEXPORTER_SECRET 02 03\n");
+    }
+
+    #[test]
+    fn key_log_sink() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (port, server) = tls_server(false);
+        let sink = Shared::default();
+
+        let mut tls = ca_config();
+        tls.set_key_log(sink.clone());
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+        });
+
+        server.join().unwrap().unwrap();
+
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let labels: Vec<_> = log.lines().filter_map(|l| l.split(' ').next()).collect();
+
+        assert!(labels.contains(&"CLIENT_HANDSHAKE_TRAFFIC_SECRET"));
+        assert!(labels.contains(&"CLIENT_TRAFFIC_SECRET_0"));
+
+        // Client random of 32 bytes.
+        assert!(
+            log.lines()
+                .all(|l| l.split(' ').nth(1).unwrap().len() == 64)
+        );
+    }
+}
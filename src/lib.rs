@@ -1,8 +1,26 @@
 #![allow(dead_code)]
 
+mod connect;
+mod dns;
 mod http1;
+mod info;
+mod keylog;
+mod pinning;
+mod proxy;
+mod proxy_header;
+mod revocation;
+mod sessions;
+mod sockopt;
+mod socks;
 mod stream;
+#[cfg(test)]
+mod testing;
+mod timer;
 mod tls_client;
+mod tls_config;
+mod tls_options;
+mod tofu;
+mod url;
 
 #[cfg(test)]
 mod tests {
@@ -60,7 +78,10 @@ mod tests {
                 roots: TLS_SERVER_ROOTS.into(),
             };
 
-            let cfg = ClientConfig::builder()
+            let provider = tls_options::TlsOptions::new().provider().unwrap();
+            let cfg = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
                 .with_root_certificates(certs)
                 .with_no_client_auth();
 
@@ -121,10 +142,17 @@ mod tests {
         .as_bytes();
 
         let task = async move {
-            let mut client = match TlsClient::create(None, "www.rust-lang.org") {
-                Ok(cl) => cl.await.expect("failure of client"),
-                Err(e) => panic!("{}", e),
-            };
+            let url = url::Url::parse("https://www.rust-lang.org").unwrap();
+            let tcp = connect::TcpConnect::new("www.rust-lang.org", 443, &dns::GaiResolver);
+            let cfg = tls_config::TlsConfig::new()
+                .client_config("www.rust-lang.org", 443)
+                .unwrap();
+
+            let mut client =
+                match TlsClient::create(cfg, &url, url.host(), Box::pin(tcp), None, None) {
+                    Ok(cl) => cl.await.expect("failure of client"),
+                    Err(e) => panic!("{}", e),
+                };
 
             let mut buf: [u8; 4096] = [0u8; 4096];
 
@@ -150,8 +178,9 @@ mod tests {
 
         let res = rt.block_on(async {
             let mut req = ReqBuilder::new(Method::GET);
+            let url = url::Url::parse("https://www.rust-lang.org").unwrap();
 
-            let mut client = Client::connect("www.rust-lang.org", "tunnel-test/0.0.1", None)
+            let mut client = Client::connect(&url, "tunnel-test/0.0.1", None)
                 .await
                 .unwrap();
             req.add_headers(vec![("Test-header", "Test-value")]);
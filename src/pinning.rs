@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::hash::{Hash, HashAlgorithm};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::{CertificateError, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use rustls_pki_types::{CertificateDer, ServerName, TrustAnchor, UnixTime};
+use webpki::{EndEntityCert, KeyUsage, VerifiedPath};
+
+/// SHA-256 hash of a certificate's SubjectPublicKeyInfo,
+/// the `pin-sha256` of RFC 7469.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpkiPin([u8; 32]);
+
+impl SpkiPin {
+    pub fn from_bytes(hash: [u8; 32]) -> Self {
+        Self(hash)
+    }
+
+    /// Parses a base64 pin, as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    pub fn from_base64(pin: &str) -> Option<Self> {
+        let bytes = base64_decode(pin.trim())?;
+
+        bytes.try_into().ok().map(Self)
+    }
+}
+
+/// Decodes padded standard base64, `None` if the input isn't valid.
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.as_bytes();
+
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+
+    for chunk in input.chunks(4) {
+        let pad = chunk.iter().rev().take_while(|c| **c == b'=').count();
+
+        if pad > 2 {
+            return None;
+        }
+
+        let mut word = 0;
+
+        for c in &chunk[..4 - pad] {
+            word = (word << 6) | value(*c)?;
+        }
+
+        word <<= 6 * pad;
+
+        let bytes = [(word >> 16) as u8, (word >> 8) as u8, word as u8];
+        out.extend_from_slice(&bytes[..3 - pad]);
+    }
+
+    Some(out)
+}
+
+/// SHA-256 implementation of `provider`.
+pub(crate) fn sha256(provider: &CryptoProvider) -> Option<&'static dyn Hash> {
+    // Every TLS 1.3 provider ships SHA-256 for its mandatory suite.
+    provider
+        .cipher_suites
+        .iter()
+        .filter_map(|suite| suite.tls13())
+        .map(|suite| suite.common.hash_provider)
+        .find(|hash| hash.algorithm() == HashAlgorithm::SHA256)
+}
+
+/// Pins required from servers, by host name.
+///
+/// The path from the certificate of a pinned host to a trusted root must hold
+/// a certificate whose key matches one of its pins, on top of being trusted as usual.
+/// Certificates served along which aren't part of that path don't count.
+/// Hosts without pins are only verified as usual.
+#[derive(Debug, Clone, Default)]
+pub struct PinSet {
+    hosts: HashMap<String, Vec<SpkiPin>>,
+    report_only: bool,
+}
+
+impl PinSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `pin` to the pins accepted from `host`.
+    pub fn add(&mut self, host: &str, pin: SpkiPin) -> &mut Self {
+        self.hosts
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(pin);
+
+        self
+    }
+
+    /// Only logs pin mismatches instead of failing the handshake,
+    /// to try pins out before enforcing them.
+    pub fn set_report_only(&mut self, report_only: bool) -> &mut Self {
+        self.report_only = report_only;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    fn get(&self, host: &str) -> Option<&[SpkiPin]> {
+        self.hosts
+            .get(&host.to_ascii_lowercase())
+            .map(|pins| pins.as_slice())
+    }
+}
+
+/// Verifier running the checks of another one,
+/// then requiring a pinned key on a path from the server's certificate to a root.
+pub(crate) struct PinningVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Arc<PinSet>,
+    roots: Arc<RootCertStore>,
+    supported: WebPkiSupportedAlgorithms,
+    sha256: &'static dyn Hash,
+}
+
+impl std::fmt::Debug for PinningVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PinningVerifier")
+            .field("inner", &self.inner)
+            .field("pins", &self.pins)
+            .finish()
+    }
+}
+
+impl PinningVerifier {
+    /// Returns `None` if `provider` has no SHA-256 implementation.
+    pub(crate) fn new(
+        inner: Arc<dyn ServerCertVerifier>,
+        pins: Arc<PinSet>,
+        roots: Arc<RootCertStore>,
+        provider: &CryptoProvider,
+    ) -> Option<Self> {
+        let sha256 = sha256(provider)?;
+
+        Some(Self {
+            inner,
+            pins,
+            roots,
+            supported: provider.signature_verification_algorithms,
+            sha256,
+        })
+    }
+
+    /// Pin of the DER encoded SubjectPublicKeyInfo `spki`.
+    fn pin_of(&self, spki: &[u8]) -> SpkiPin {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(self.sha256.hash(spki).as_ref());
+
+        SpkiPin(hash)
+    }
+
+    /// Pin of a trust anchor, whose SubjectPublicKeyInfo is kept without its outer sequence.
+    fn anchor_pin(&self, anchor: &TrustAnchor<'_>) -> SpkiPin {
+        let contents = anchor.subject_public_key_info.as_ref();
+        let mut spki = vec![0x30];
+
+        // The length in the fewest bytes, as DER wants it.
+        match contents.len() {
+            len @ 0..=0x7F => spki.push(len as u8),
+            len => {
+                let bytes = len.to_be_bytes();
+                let bytes = &bytes[bytes.iter().take_while(|b| **b == 0).count()..];
+
+                spki.push(0x80 | bytes.len() as u8);
+                spki.extend_from_slice(bytes);
+            }
+        }
+
+        spki.extend_from_slice(contents);
+
+        self.pin_of(&spki)
+    }
+
+    /// Whether a certificate of `path`, the trust anchor included, has one of `pins`.
+    fn is_pinned(&self, path: &VerifiedPath<'_>, pins: &[SpkiPin]) -> bool {
+        let certs = std::iter::once(&**path.end_entity()).chain(path.intermediate_certificates());
+
+        certs
+            .map(|cert| self.pin_of(cert.subject_public_key_info().as_ref()))
+            .chain(std::iter::once(self.anchor_pin(path.anchor())))
+            .any(|pin| pins.contains(&pin))
+    }
+
+    /// Whether some path from `end_entity` to a root, through `intermediates`, has one of `pins`.
+    fn has_pinned_path(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        pins: &[SpkiPin],
+        now: UnixTime,
+    ) -> Result<bool, Error> {
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|_| Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        // Paths without a pin are turned down, so that webpki goes on with the next one.
+        let pinned = |path: &VerifiedPath<'_>| match self.is_pinned(path, pins) {
+            true => Ok(()),
+            false => Err(webpki::Error::UnknownIssuer),
+        };
+
+        let path = cert.verify_for_usage(
+            self.supported.all,
+            &self.roots.roots,
+            intermediates,
+            now,
+            KeyUsage::server_auth(),
+            None,
+            Some(&pinned),
+        );
+
+        Ok(path.is_ok())
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let host = server_name.to_str();
+
+        let pins = match self.pins.get(&host) {
+            Some(pins) => pins,
+            None => return Ok(verified),
+        };
+
+        if self.has_pinned_path(end_entity, intermediates, pins, now)? {
+            return Ok(verified);
+        }
+
+        if self.pins.report_only {
+            log::warn!("no pinned key on the paths of the chain served by {}", host);
+
+            return Ok(verified);
+        }
+
+        Err(Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PinSet, PinningVerifier, SpkiPin, base64_decode};
+    use crate::tls_options::TlsOptions;
+    use rustls::RootCertStore;
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+    use std::sync::Arc;
+
+    const SERVER_PIN: &str = "EnKPT83ZRPfZhqvsmZvYRUBS7Ibj8dvsMfbhqXYpQ5w=";
+    const CLIENT_PIN: &str = "kfVd9cmoaR50R3bPWxlYj90cMTlobwkIVEkNebjk44Q=";
+    const CA_PIN: &str = "12q9eydZJDRGvJuvD7B8g1Edp0NCPtvVjzJ7+okYOJ0=";
+
+    const CA_PEM: &[u8] = include_bytes!("../testdata/ca.pem");
+    const SERVER_PEM: &[u8] = include_bytes!("../testdata/server.pem");
+    const FORGED_CA_PEM: &[u8] = include_bytes!("../testdata/forged-ca.pem");
+    const FORGED_SERVER_PEM: &[u8] = include_bytes!("../testdata/forged-server.pem");
+
+    #[test]
+    fn decode_base64() {
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+        assert_eq!(base64_decode("Zm8=").unwrap(), b"fo");
+        assert_eq!(base64_decode("Zm9v").unwrap(), b"foo");
+
+        assert!(base64_decode("Zm9").is_none());
+        assert!(base64_decode("Z===").is_none());
+        assert!(base64_decode("Zm9*").is_none());
+
+        assert!(SpkiPin::from_base64(SERVER_PIN).is_some());
+        assert!(SpkiPin::from_base64("Zm9v").is_none());
+    }
+
+    fn verify(pins: PinSet, host: &str) -> bool {
+        verify_chain(pins, host, SERVER_PEM, &[])
+    }
+
+    /// Verifies `leaf` served along `intermediates`, trusting the test CA and the forged one.
+    fn verify_chain(pins: PinSet, host: &str, leaf: &[u8], intermediates: &[&[u8]]) -> bool {
+        let provider = TlsOptions::new().provider().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        for ca in [CA_PEM, FORGED_CA_PEM] {
+            roots
+                .add(CertificateDer::from_pem_slice(ca).unwrap())
+                .unwrap();
+        }
+        let roots = Arc::new(roots);
+
+        let inner =
+            WebPkiServerVerifier::builder_with_provider(Arc::clone(&roots), provider.clone())
+                .build()
+                .unwrap();
+
+        let verifier = PinningVerifier::new(inner, Arc::new(pins), roots, &provider).unwrap();
+
+        let leaf = CertificateDer::from_pem_slice(leaf).unwrap();
+        let intermediates = intermediates
+            .iter()
+            .map(|pem| CertificateDer::from_pem_slice(pem).unwrap())
+            .collect::<Vec<_>>();
+        let name = ServerName::try_from(host).unwrap();
+
+        verifier
+            .verify_server_cert(&leaf, &intermediates, &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn pinned_chain() {
+        let server = SpkiPin::from_base64(SERVER_PIN).unwrap();
+        let client = SpkiPin::from_base64(CLIENT_PIN).unwrap();
+
+        let mut pins = PinSet::new();
+        pins.add("localhost", client).add("LocalHost", server);
+        assert!(verify(pins, "localhost"));
+
+        let mut pins = PinSet::new();
+        pins.add("localhost", client);
+        assert!(!verify(pins.clone(), "localhost"));
+
+        pins.set_report_only(true);
+        assert!(verify(pins, "localhost"));
+
+        // Unpinned hosts are verified as usual.
+        let mut pins = PinSet::new();
+        pins.add("example.com", client);
+        assert!(verify(pins, "localhost"));
+    }
+
+    #[test]
+    fn pinned_root() {
+        let mut pins = PinSet::new();
+        pins.add("localhost", SpkiPin::from_base64(CA_PIN).unwrap());
+
+        let verify =
+            |leaf, intermediates| verify_chain(pins.clone(), "localhost", leaf, intermediates);
+
+        assert!(verify(SERVER_PEM, &[]));
+        assert!(verify(SERVER_PEM, &[CA_PEM]));
+
+        // Trusted through another CA, sending the pinned one along doesn't help.
+        assert!(!verify(FORGED_SERVER_PEM, &[]));
+        assert!(!verify(FORGED_SERVER_PEM, &[CA_PEM]));
+    }
+}
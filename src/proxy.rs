@@ -0,0 +1,187 @@
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+
+use lamp::io::{AsyncRead, AsyncWrite};
+use memchr::memmem;
+
+use super::connect::Tcp;
+use super::http1::client::Method;
+use super::http1::request::ReqBuilder;
+use super::http1::response::DataDecoder;
+use super::url::Host;
+
+/// Upper bound on the size of the proxy's answer to `CONNECT`.
+const MAX_REPLY: usize = 8 * 1024;
+
+/// Asks the HTTP proxy on the other end of `io` to open a tunnel to `target` on `port`.
+///
+/// Once the proxy accepted, `io` carries the raw bytes of the tunnel,
+/// so the TLS handshake with the origin can run right over it.
+pub(crate) async fn tunnel(mut io: Tcp, target: &Host, port: u16) -> io::Result<Tcp> {
+    let authority = format!("{}:{}", target, port);
+
+    let mut req = ReqBuilder::new(Method::CONNECT);
+    req.set_route(&authority)
+        .add_headers([("Host", authority.as_str())]);
+
+    write_all(&mut io, &req.construct()).await?;
+
+    let head = read_head(&mut io).await?;
+
+    let mut decoder = DataDecoder::new();
+    decoder
+        .decode(&head)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let code = match decoder.get_resp() {
+        Some(resp) => resp.code(),
+        None => {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "incomplete proxy reply");
+
+            return Err(err);
+        }
+    };
+
+    if !(200..300).contains(&code) {
+        let msg = format!("proxy refused to tunnel to {}: status {}", authority, code);
+
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg));
+    }
+
+    Ok(io)
+}
+
+/// Writes all of `buf` to `io` and flushes it.
+pub(crate) async fn write_all<IO: AsyncWrite + Unpin>(
+    io: &mut IO,
+    mut buf: &[u8],
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *io).poll_write(cx, buf)).await?;
+
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        buf = &buf[n..];
+    }
+
+    poll_fn(|cx| Pin::new(&mut *io).poll_flush(cx)).await
+}
+
+/// Reads the status line and headers of the reply, up to the empty line.
+///
+/// The proxy sends nothing past the headers of a successful reply
+/// until the client speaks, so no byte of the tunnel is consumed.
+async fn read_head(io: &mut Tcp) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut *io).poll_read(cx, &mut buf)).await?;
+
+        if n == 0 {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "proxy closed the connection");
+
+            return Err(err);
+        }
+
+        head.extend_from_slice(&buf[..n]);
+
+        if let Some(end) = memmem::find(&head, b"\r\n\r\n") {
+            head.truncate(end + 4);
+
+            return Ok(head);
+        }
+
+        if head.len() > MAX_REPLY {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "proxy reply too large");
+
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{OK, USER_AGENT, run, serve};
+    use crate::url::Url;
+    use std::io::{self, Read, Write};
+
+    #[test]
+    fn tunnel_through_proxy() {
+        // Stub proxy which accepts the tunnel and then plays the origin itself.
+        let (port, proxy) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"CONNECT origin.test:80 HTTP/1.1\r\n"));
+            assert!(String::from_utf8_lossy(&buf[..len]).contains("\r\nHost: origin.test:80\r\n"));
+
+            sock.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+
+            let len = sock.read(&mut buf).unwrap();
+            assert!(buf[..len].starts_with(b"GET /health HTTP/1.1\r\n"));
+
+            sock.write_all(OK).unwrap();
+        });
+
+        run(async move {
+            // Never resolved locally, only the proxy needs to know the origin.
+            let url = Url::parse("http://origin.test/health").unwrap();
+            let proxy = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .proxy(&proxy)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+            assert_eq!(resp.content(), Some("ok".as_bytes()));
+        });
+
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn proxy_refuses_tunnel() {
+        let (port, proxy) = serve(|mut sock| {
+            let mut buf = [0u8; 1024];
+
+            let _ = sock.read(&mut buf).unwrap();
+
+            let resp = "HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n";
+            sock.write_all(resp.as_bytes()).unwrap();
+        });
+
+        run(async move {
+            let url = Url::parse("https://origin.test/").unwrap();
+            let proxy = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+
+            let err = match ClientBuilder::new(USER_AGENT)
+                .proxy(&proxy)
+                .connect(&url)
+                .await
+            {
+                Ok(_) => panic!("tunnel was refused but the client connected"),
+                Err(e) => e,
+            };
+
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(err.to_string().contains("407"));
+        });
+
+        proxy.join().unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// Signature opening every version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2, `PROXY` command.
+const VERSION_COMMAND: u8 = 0x21;
+
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+/// Application protocol the client asked for, like `h2`.
+pub const TLV_ALPN: u8 = 0x01;
+
+/// Host name the client asked for, like its SNI.
+pub const TLV_AUTHORITY: u8 = 0x02;
+
+/// Opaque identifier of the client connection, up to 128 bytes.
+pub const TLV_UNIQUE_ID: u8 = 0x05;
+
+/// Version of the PROXY protocol, the first being text and the second binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyVersion {
+    V1,
+    V2,
+}
+
+/// HAProxy PROXY protocol header, sent ahead of anything else on every connection
+/// so that the server learns the addresses of the original client connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProxyHeader {
+    version: ProxyVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+    tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    /// A header telling the connection came from `source` and went to `destination`.
+    /// When one address is IPv4 and the other IPv6, the IPv4 one is sent mapped to IPv6.
+    pub fn new(version: ProxyVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            version,
+            source,
+            destination,
+            tlvs: Vec::new(),
+        }
+    }
+
+    /// Appends a type-length-value field, only version 2 headers can carry them.
+    pub fn add_tlv(&mut self, kind: u8, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.tlvs.push((kind, value.into()));
+        self
+    }
+
+    /// The header as written on the wire.
+    pub(crate) fn encode(&self) -> io::Result<Vec<u8>> {
+        let (source, destination) = match (self.source.ip(), self.destination.ip()) {
+            (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => {
+                (mapped(self.source), mapped(self.destination))
+            }
+            _ => (self.source, self.destination),
+        };
+
+        match self.version {
+            ProxyVersion::V1 => self.v1(source, destination),
+            ProxyVersion::V2 => self.v2(source, destination),
+        }
+    }
+
+    fn v1(&self, source: SocketAddr, destination: SocketAddr) -> io::Result<Vec<u8>> {
+        if !self.tlvs.is_empty() {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PROXY protocol version 1 can't carry TLVs",
+            );
+
+            return Err(err);
+        }
+
+        let family = match source {
+            SocketAddr::V4(_) => "TCP4",
+            SocketAddr::V6(_) => "TCP6",
+        };
+
+        let line = format!(
+            "PROXY {} {} {} {} {}\r\n",
+            family,
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port(),
+        );
+
+        Ok(line.into_bytes())
+    }
+
+    fn v2(&self, source: SocketAddr, destination: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(36);
+
+        let family = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                body.extend_from_slice(&src.octets());
+                body.extend_from_slice(&dst.octets());
+                FAMILY_TCP4
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                body.extend_from_slice(&src.octets());
+                body.extend_from_slice(&dst.octets());
+                FAMILY_TCP6
+            }
+            _ => unreachable!("addresses of different families are mapped to IPv6"),
+        };
+
+        body.extend_from_slice(&source.port().to_be_bytes());
+        body.extend_from_slice(&destination.port().to_be_bytes());
+
+        for (kind, value) in &self.tlvs {
+            body.push(*kind);
+            body.extend_from_slice(&length(value.len())?.to_be_bytes());
+            body.extend_from_slice(value);
+        }
+
+        let mut header = Vec::with_capacity(16 + body.len());
+        header.extend_from_slice(&SIGNATURE);
+        header.push(VERSION_COMMAND);
+        header.push(family);
+        header.extend_from_slice(&length(body.len())?.to_be_bytes());
+        header.extend_from_slice(&body);
+
+        Ok(header)
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+/// Lengths of version 2 headers are 16 bits.
+fn length(len: usize) -> io::Result<u16> {
+    u16::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "PROXY protocol header longer than 65535 bytes",
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProxyHeader, ProxyVersion, TLV_AUTHORITY};
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{OK, USER_AGENT, ca_config, run, serve, server_config};
+    use crate::url::Url;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    #[test]
+    fn version_1() {
+        let header = ProxyHeader::new(
+            ProxyVersion::V1,
+            "192.0.2.1:56324".parse().unwrap(),
+            "198.51.100.7:443".parse().unwrap(),
+        );
+
+        assert_eq!(
+            header.encode().unwrap(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n"
+        );
+
+        let header = ProxyHeader::new(
+            ProxyVersion::V1,
+            "192.0.2.1:56324".parse().unwrap(),
+            "[2001:db8::7]:443".parse().unwrap(),
+        );
+
+        assert_eq!(
+            header.encode().unwrap(),
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::7 56324 443\r\n"
+        );
+
+        let mut header = header;
+        header.add_tlv(TLV_AUTHORITY, "example.com");
+        assert!(header.encode().is_err());
+    }
+
+    #[test]
+    fn version_2_with_tlvs() {
+        let mut header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "192.0.2.1:56324".parse().unwrap(),
+            "198.51.100.7:443".parse().unwrap(),
+        );
+        header.add_tlv(TLV_AUTHORITY, "a.io");
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 19]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7, 0xdc, 0x04, 0x01, 0xbb]);
+        expected.extend_from_slice(&[0x02, 0x00, 0x04]);
+        expected.extend_from_slice(b"a.io");
+
+        assert_eq!(header.encode().unwrap(), expected);
+
+        header.add_tlv(0xe0, vec![0; 70_000]);
+        assert!(header.encode().is_err());
+    }
+
+    #[test]
+    fn proxy_protocol_headers() {
+        let (plain_port, plain_server) = serve(|mut sock| {
+            // The header is written on its own, ahead of the request.
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+
+            while !head.ends_with(b"\r\n\r\n") {
+                let len = sock.read(&mut buf).unwrap();
+                assert_ne!(len, 0);
+                head.extend_from_slice(&buf[..len]);
+            }
+
+            let expected = "PROXY TCP4 192.0.2.1 198.51.100.7 56324 80\r\nGET / HTTP/1.1\r\n";
+            assert!(head.starts_with(expected.as_bytes()));
+
+            sock.write_all(OK).unwrap();
+        });
+
+        let (tls_port, tls_server) = serve(|mut sock| {
+            // The header comes before the handshake, its length in bytes 14 and 15.
+            let mut head = [0u8; 16];
+            sock.read_exact(&mut head).unwrap();
+            let mut rest = vec![0u8; u16::from_be_bytes([head[14], head[15]]) as usize];
+            sock.read_exact(&mut rest).unwrap();
+
+            let conn = rustls::ServerConnection::new(Arc::new(server_config(false))).unwrap();
+            let mut tls = rustls::StreamOwned::new(conn, sock);
+
+            let mut buf = [0u8; 1024];
+            let _ = tls.read(&mut buf).unwrap();
+            tls.write_all(OK).unwrap();
+            tls.flush().unwrap();
+
+            (head, rest)
+        });
+
+        run(async move {
+            let source = "192.0.2.1:56324".parse().unwrap();
+
+            let url = format!("http://127.0.0.1:{}/", plain_port);
+            let header =
+                ProxyHeader::new(ProxyVersion::V1, source, "198.51.100.7:80".parse().unwrap());
+
+            let mut header_v2 = ProxyHeader::new(
+                ProxyVersion::V2,
+                source,
+                "198.51.100.7:443".parse().unwrap(),
+            );
+            header_v2.add_tlv(TLV_AUTHORITY, "localhost");
+            let tls_url = format!("https://localhost:{}/", tls_port);
+
+            for (url, header) in [(url, header), (tls_url, header_v2)] {
+                let url = Url::parse(&url).unwrap();
+
+                let mut client = ClientBuilder::new(USER_AGENT)
+                    .tls(ca_config())
+                    .proxy_header(header)
+                    .connect(&url)
+                    .await
+                    .unwrap();
+
+                let resp = client.execute(ReqBuilder::new(Method::GET)).await;
+                assert_eq!(resp.unwrap().unwrap().content(), Some("ok".as_bytes()));
+            }
+        });
+
+        plain_server.join().unwrap();
+        let (head, rest) = tls_server.join().unwrap();
+
+        assert_eq!(&head[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(head[12..14], [0x21, 0x11]);
+        assert_eq!(
+            rest[..12],
+            [192, 0, 2, 1, 198, 51, 100, 7, 0xdc, 0x04, 0x01, 0xbb]
+        );
+        assert_eq!(rest[12..], *b"\x02\x00\x09localhost");
+    }
+}
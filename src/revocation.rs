@@ -0,0 +1,618 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{VerifierBuilderError, WebPkiServerVerifier};
+use rustls::crypto::hash::Hash;
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::{
+    CertificateError, DigitallySignedStruct, Error, OtherError, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{
+    CertificateDer, CertificateRevocationListDer, ServerName, TrustAnchor, UnixTime,
+};
+use sha1::{Digest, Sha1};
+use webpki::{EndEntityCert, KeyUsage};
+use x509_cert::der::asn1::{AnyRef, BitString};
+use x509_cert::der::oid::{AssociatedOid, ObjectIdentifier};
+use x509_cert::der::{Decode, Encode, Tag};
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoRef};
+use x509_ocsp::{BasicOcspResponse, OcspGeneralizedTime, OcspResponse, OcspResponseStatus};
+
+use super::pinning;
+use super::tls_config::pem_err;
+
+const SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+const SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+
+/// id-kp-OCSPSigning, 1.3.6.1.5.5.7.3.9, as webpki wants it.
+const OCSP_SIGNING: &[u8] = &[0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+
+/// Tolerated difference between our clock and the responder's, in seconds.
+const CLOCK_SKEW: u64 = 5 * 60;
+
+/// Revocation checks of server certificates.
+///
+/// The server's certificate is looked up in the CRLs and in the OCSP response
+/// stapled to the handshake, intermediates aren't checked. A revoked certificate
+/// always fails the handshake, what happens when its status is unknown depends
+/// on [`Revocation::set_hard_fail`].
+///
+/// An OCSP response without a next update is taken as current however old it is,
+/// as RFC 6960 has it.
+#[derive(Debug, Clone, Default)]
+pub struct Revocation {
+    crls: Vec<CertificateRevocationListDer<'static>>,
+    hard_fail: bool,
+}
+
+impl Revocation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds every CRL of PEM data.
+    pub fn add_crl_pem(&mut self, pem: &[u8]) -> io::Result<&mut Self> {
+        let crls = CertificateRevocationListDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_err)?;
+
+        if crls.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "no CRL found");
+
+            return Err(err);
+        }
+
+        self.crls.extend(crls);
+
+        Ok(self)
+    }
+
+    /// Adds every CRL of a PEM file.
+    pub fn add_crl_file(&mut self, path: impl AsRef<Path>) -> io::Result<&mut Self> {
+        let pem = fs::read(path)?;
+
+        self.add_crl_pem(&pem)
+    }
+
+    /// Fails the handshake when neither a CRL nor a valid stapled OCSP response
+    /// tell the status of the certificate, or when a CRL has expired.
+    ///
+    /// Off by default, such certificates are accepted and invalid staples only logged.
+    pub fn set_hard_fail(&mut self, hard_fail: bool) -> &mut Self {
+        self.hard_fail = hard_fail;
+        self
+    }
+}
+
+#[derive(Debug)]
+/// Why a stapled OCSP response was rejected.
+pub enum OcspError {
+    /// Not a valid DER encoding of a response.
+    Malformed,
+
+    /// The responder couldn't answer, with the response status of RFC 6960.
+    Status(u8),
+
+    /// Not a basic response, the only type of RFC 6960.
+    UnsupportedType,
+
+    /// The certificate doesn't chain up to a trusted root,
+    /// so there is no issuer to check the response against.
+    UnknownIssuer,
+
+    /// Signed neither by the issuer nor by a responder it delegated to.
+    BadSignature,
+
+    /// The response doesn't cover the server's certificate,
+    /// or names it with a hash we don't implement.
+    WrongCertificate,
+
+    /// The response was produced for a time still to come.
+    NotYetValid,
+
+    /// The response is past its next update.
+    Expired,
+}
+
+impl std::fmt::Display for OcspError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#?}", self)
+    }
+}
+
+impl std::error::Error for OcspError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+impl From<x509_cert::der::Error> for OcspError {
+    fn from(_: x509_cert::der::Error) -> Self {
+        OcspError::Malformed
+    }
+}
+
+/// Contents of the element encoded in `der`, without its tag and length.
+fn contents(der: &[u8]) -> Result<&[u8], OcspError> {
+    Ok(AnyRef::from_der(der)?.value())
+}
+
+/// Whether `signature` of `message` was made with the key of the DER encoded `spki`,
+/// using the signature algorithm `alg`.
+fn verify_signed(
+    supported: &WebPkiSupportedAlgorithms,
+    spki: &[u8],
+    alg: &AlgorithmIdentifierOwned,
+    message: &[u8],
+    signature: &BitString,
+) -> Result<bool, OcspError> {
+    let spki = SubjectPublicKeyInfoRef::from_der(spki)?;
+    let key_alg = spki.algorithm.to_der()?;
+    let alg = alg.to_der()?;
+
+    let (key_alg, alg) = (contents(&key_alg)?, contents(&alg)?);
+    let signature = signature.as_bytes().ok_or(OcspError::Malformed)?;
+    let key = spki.subject_public_key.raw_bytes();
+
+    let valid = supported
+        .all
+        .iter()
+        .filter(|algo| algo.public_key_alg_id().as_ref() == key_alg)
+        .filter(|algo| algo.signature_alg_id().as_ref() == alg)
+        .any(|algo| algo.verify_signature(key, message, signature).is_ok());
+
+    Ok(valid)
+}
+
+/// Encoding of a `SEQUENCE` of `contents`, which webpki keeps names and keys of anchors as.
+fn sequence(contents: &[u8]) -> Result<Vec<u8>, OcspError> {
+    Ok(AnyRef::new(Tag::Sequence, contents)?.to_der()?)
+}
+
+/// Checks an OCSP response about `leaf`, issued by `issuer`.
+///
+/// The response is matched to the certificate by its serial number
+/// and the hashes of its issuer's name and key, and must be signed by that issuer
+/// or by a responder it delegated to.
+fn check_ocsp(
+    response: &[u8],
+    leaf: &EndEntityCert<'_>,
+    issuer: &TrustAnchor<'_>,
+    supported: &WebPkiSupportedAlgorithms,
+    sha256: Option<&dyn Hash>,
+    now: UnixTime,
+) -> Result<CertStatus, OcspError> {
+    let issuer_name = sequence(leaf.issuer())?;
+    let issuer_spki = sequence(issuer.subject_public_key_info.as_ref())?;
+    let issuer_key = SubjectPublicKeyInfoRef::from_der(&issuer_spki)?
+        .subject_public_key
+        .raw_bytes();
+
+    let digest = |alg: &ObjectIdentifier, data: &[u8]| match *alg {
+        SHA1 => Some(Sha1::digest(data).to_vec()),
+        SHA256 => sha256.map(|hash| hash.hash(data).as_ref().to_vec()),
+        _ => None,
+    };
+
+    let response = OcspResponse::from_der(response)?;
+
+    if response.response_status != OcspResponseStatus::Successful {
+        return Err(OcspError::Status(response.response_status as u8));
+    }
+
+    let bytes = response.response_bytes.ok_or(OcspError::Malformed)?;
+
+    if bytes.response_type != BasicOcspResponse::OID {
+        return Err(OcspError::UnsupportedType);
+    }
+
+    let basic = BasicOcspResponse::from_der(bytes.response.as_bytes())?;
+
+    // The decoder only takes DER, so encoding again gives back the bytes signed.
+    let tbs = basic.tbs_response_data.to_der()?;
+    let (alg, signature) = (&basic.signature_algorithm, &basic.signature);
+
+    if !verify_signed(supported, &issuer_spki, alg, &tbs, signature)? {
+        let mut delegated = false;
+
+        for cert in basic.certs.iter().flatten() {
+            let der = CertificateDer::from(cert.to_der()?);
+            let responder = EndEntityCert::try_from(&der).map_err(|_| OcspError::Malformed)?;
+
+            // Issued by the issuer of the leaf, valid now and for signing OCSP responses.
+            let authorized = responder
+                .verify_for_usage(
+                    supported.all,
+                    std::slice::from_ref(issuer),
+                    &[],
+                    now,
+                    KeyUsage::required(OCSP_SIGNING),
+                    None,
+                    None,
+                )
+                .is_ok();
+
+            let spki = responder.subject_public_key_info();
+
+            if authorized && verify_signed(supported, spki.as_ref(), alg, &tbs, signature)? {
+                delegated = true;
+                break;
+            }
+        }
+
+        if !delegated {
+            return Err(OcspError::BadSignature);
+        }
+    }
+
+    let now = now.as_secs();
+
+    for single in basic.tbs_response_data.responses {
+        let id = single.cert_id;
+
+        if id.serial_number.as_bytes() != leaf.serial() {
+            continue;
+        }
+
+        // Another issuer may well have given out the same serial.
+        let alg = &id.hash_algorithm.oid;
+        let name_matches =
+            digest(alg, &issuer_name).is_some_and(|h| h == id.issuer_name_hash.as_bytes());
+        let key_matches =
+            digest(alg, issuer_key).is_some_and(|h| h == id.issuer_key_hash.as_bytes());
+
+        if !name_matches || !key_matches {
+            continue;
+        }
+
+        let status = match single.cert_status {
+            x509_ocsp::CertStatus::Good(_) => CertStatus::Good,
+            x509_ocsp::CertStatus::Revoked(_) => CertStatus::Revoked,
+            x509_ocsp::CertStatus::Unknown(_) => CertStatus::Unknown,
+        };
+
+        if single.this_update.0.to_unix_duration().as_secs() > now + CLOCK_SKEW {
+            return Err(OcspError::NotYetValid);
+        }
+
+        let expired =
+            |next: &OcspGeneralizedTime| next.0.to_unix_duration().as_secs() + CLOCK_SKEW < now;
+
+        if single.next_update.as_ref().is_some_and(expired) {
+            return Err(OcspError::Expired);
+        }
+
+        return Ok(status);
+    }
+
+    Err(OcspError::WrongCertificate)
+}
+
+/// Verifier running the usual webpki checks along with the CRLs,
+/// then the checks of the stapled OCSP response.
+///
+/// Only the server's certificate is checked, intermediates aren't.
+pub(crate) struct RevocationVerifier {
+    /// Lets certificates without a known status through.
+    lenient: Arc<WebPkiServerVerifier>,
+
+    /// Fails certificates without a known status,
+    /// only there when failing hard with CRLs to look in.
+    strict: Option<Arc<WebPkiServerVerifier>>,
+
+    roots: Arc<RootCertStore>,
+    supported: WebPkiSupportedAlgorithms,
+    sha256: Option<&'static dyn Hash>,
+    hard_fail: bool,
+}
+
+impl std::fmt::Debug for RevocationVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RevocationVerifier")
+            .field("lenient", &self.lenient)
+            .field("strict", &self.strict)
+            .field("hard_fail", &self.hard_fail)
+            .finish()
+    }
+}
+
+impl RevocationVerifier {
+    pub(crate) fn new(
+        roots: Arc<RootCertStore>,
+        revocation: &Revocation,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, VerifierBuilderError> {
+        let builder = || {
+            WebPkiServerVerifier::builder_with_provider(Arc::clone(&roots), Arc::clone(&provider))
+                .with_crls(revocation.crls.iter().cloned())
+                .only_check_end_entity_revocation()
+        };
+
+        let lenient = builder().allow_unknown_revocation_status().build()?;
+
+        let strict = match revocation.hard_fail && !revocation.crls.is_empty() {
+            true => Some(builder().enforce_revocation_expiration().build()?),
+            false => None,
+        };
+
+        Ok(Self {
+            lenient,
+            strict,
+            roots,
+            supported: provider.signature_verification_algorithms,
+            sha256: pinning::sha256(&provider),
+            hard_fail: revocation.hard_fail,
+        })
+    }
+
+    /// Checks the OCSP response stapled for `end_entity`.
+    fn check_staple(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        response: &[u8],
+        now: UnixTime,
+    ) -> Result<CertStatus, OcspError> {
+        let leaf = EndEntityCert::try_from(end_entity).map_err(|_| OcspError::Malformed)?;
+
+        // Anyone can serve a certificate with the issuer's name,
+        // the issuer is the one on the path webpki verified.
+        let path = leaf
+            .verify_for_usage(
+                self.supported.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                KeyUsage::server_auth(),
+                None,
+                None,
+            )
+            .map_err(|_| OcspError::UnknownIssuer)?;
+
+        let issuer = match path.intermediate_certificates().next() {
+            Some(cert) => webpki::anchor_from_trusted_cert(&cert.der())
+                .map_err(|_| OcspError::UnknownIssuer)?
+                .to_owned(),
+            None => path.anchor().to_owned(),
+        };
+
+        check_ocsp(response, &leaf, &issuer, &self.supported, self.sha256, now)
+    }
+}
+
+impl ServerCertVerifier for RevocationVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        // Fails on certificates revoked by a CRL.
+        let verified = self.lenient.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let staple = match ocsp_response {
+            [] => None,
+            response => Some(self.check_staple(end_entity, intermediates, response, now)),
+        };
+
+        let invalid = match staple {
+            Some(Ok(CertStatus::Good)) => return Ok(verified),
+
+            Some(Ok(CertStatus::Revoked)) => {
+                return Err(Error::InvalidCertificate(CertificateError::Revoked));
+            }
+
+            Some(Err(e)) => {
+                log::warn!(
+                    "invalid OCSP response stapled by {}: {}",
+                    server_name.to_str(),
+                    e
+                );
+
+                Some(e)
+            }
+
+            Some(Ok(CertStatus::Unknown)) | None => None,
+        };
+
+        if !self.hard_fail {
+            return Ok(verified);
+        }
+
+        // Without a good staple, the status has to come from a CRL.
+        if let Some(ref strict) = self.strict {
+            return strict.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            );
+        }
+
+        let err = match invalid {
+            Some(e) => CertificateError::Other(OtherError(Arc::new(e))),
+            None => CertificateError::UnknownRevocationStatus,
+        };
+
+        Err(Error::InvalidCertificate(err))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.lenient.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.lenient.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.lenient.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Revocation, RevocationVerifier};
+    use crate::tls_options::TlsOptions;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls::{CertificateError, Error, RootCertStore};
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const SERVER_PEM: &[u8] = include_bytes!("../testdata/server.pem");
+    const REVOKED_PEM: &[u8] = include_bytes!("../testdata/revoked.pem");
+    const CRL_PEM: &[u8] = include_bytes!("../testdata/crl.pem");
+
+    const OCSP_SERVER: &[u8] = include_bytes!("../testdata/ocsp-server.der");
+    const OCSP_REVOKED: &[u8] = include_bytes!("../testdata/ocsp-revoked.der");
+    const OCSP_DELEGATED: &[u8] = include_bytes!("../testdata/ocsp-delegated.der");
+
+    const FORGED_CA_PEM: &[u8] = include_bytes!("../testdata/forged-ca.pem");
+    const OCSP_FORGED: &[u8] = include_bytes!("../testdata/ocsp-forged.der");
+    const OCSP_WRONG_ISSUER: &[u8] = include_bytes!("../testdata/ocsp-wrong-issuer.der");
+
+    fn verify(revocation: &Revocation, leaf: &[u8], staple: &[u8]) -> Result<(), Error> {
+        verify_chain(revocation, leaf, &[], staple)
+    }
+
+    fn verify_chain(
+        revocation: &Revocation,
+        leaf: &[u8],
+        intermediates: &[CertificateDer<'_>],
+        staple: &[u8],
+    ) -> Result<(), Error> {
+        let provider = TlsOptions::new().provider().unwrap();
+
+        let ca = CertificateDer::from_pem_slice(include_bytes!("../testdata/ca.pem")).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(ca).unwrap();
+
+        let verifier = RevocationVerifier::new(Arc::new(roots), revocation, provider).unwrap();
+
+        let leaf = CertificateDer::from_pem_slice(leaf).unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+
+        // The fixtures are valid from October 2026 on.
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(1_900_000_000));
+
+        verifier
+            .verify_server_cert(&leaf, intermediates, &name, staple, now)
+            .map(|_| ())
+    }
+
+    #[test]
+    fn revoked_by_crl() {
+        let mut revocation = Revocation::new();
+        revocation.add_crl_pem(CRL_PEM).unwrap();
+
+        assert!(verify(&revocation, SERVER_PEM, &[]).is_ok());
+        assert_eq!(
+            verify(&revocation, REVOKED_PEM, &[]),
+            Err(Error::InvalidCertificate(CertificateError::Revoked))
+        );
+
+        revocation.set_hard_fail(true);
+        assert!(verify(&revocation, SERVER_PEM, &[]).is_ok());
+
+        assert!(Revocation::new().add_crl_pem(SERVER_PEM).is_err());
+    }
+
+    #[test]
+    fn stapled_ocsp() {
+        let mut revocation = Revocation::new();
+
+        assert!(verify(&revocation, SERVER_PEM, OCSP_SERVER).is_ok());
+        assert!(verify(&revocation, SERVER_PEM, OCSP_DELEGATED).is_ok());
+        assert_eq!(
+            verify(&revocation, REVOKED_PEM, OCSP_REVOKED),
+            Err(Error::InvalidCertificate(CertificateError::Revoked))
+        );
+
+        // Soft fail lets unknown statuses and broken staples through.
+        assert!(verify(&revocation, REVOKED_PEM, &[]).is_ok());
+        assert!(verify(&revocation, REVOKED_PEM, OCSP_SERVER).is_ok());
+        assert!(verify(&revocation, SERVER_PEM, b"garbage").is_ok());
+
+        revocation.set_hard_fail(true);
+        assert!(verify(&revocation, SERVER_PEM, OCSP_SERVER).is_ok());
+        assert!(verify(&revocation, SERVER_PEM, OCSP_DELEGATED).is_ok());
+
+        assert_eq!(
+            verify(&revocation, SERVER_PEM, &[]),
+            Err(Error::InvalidCertificate(
+                CertificateError::UnknownRevocationStatus
+            ))
+        );
+        assert!(verify(&revocation, REVOKED_PEM, OCSP_SERVER).is_err());
+        assert!(verify(&revocation, SERVER_PEM, b"garbage").is_err());
+
+        // A tampered signature is rejected.
+        let mut forged = OCSP_SERVER.to_vec();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(verify(&revocation, SERVER_PEM, &forged).is_err());
+
+        // The CRL tells the status when no OCSP response is stapled.
+        revocation.add_crl_pem(CRL_PEM).unwrap();
+        assert!(verify(&revocation, SERVER_PEM, &[]).is_ok());
+    }
+
+    #[test]
+    fn forged_issuer_rejected() {
+        let mut revocation = Revocation::new();
+        revocation.set_hard_fail(true);
+
+        // Named like the CA, with another key, and served along with a staple it signed.
+        let forged = CertificateDer::from_pem_slice(FORGED_CA_PEM).unwrap();
+
+        let chain = std::slice::from_ref(&forged);
+
+        assert!(verify_chain(&revocation, SERVER_PEM, chain, OCSP_FORGED).is_err());
+        assert!(verify_chain(&revocation, SERVER_PEM, chain, OCSP_SERVER).is_ok());
+
+        // Signed by the CA, about a certificate of the same serial from the forged one.
+        assert!(verify(&revocation, SERVER_PEM, OCSP_WRONG_ISSUER).is_err());
+    }
+
+    #[test]
+    fn non_canonical_der_rejected() {
+        let mut revocation = Revocation::new();
+        revocation.set_hard_fail(true);
+
+        // The outer length in three bytes where two do, left out of the signature.
+        let mut long = vec![0x30, 0x83, 0x00];
+        long.extend_from_slice(&OCSP_SERVER[2..]);
+        assert_eq!(&OCSP_SERVER[..2], &[0x30, 0x82]);
+
+        assert!(verify(&revocation, SERVER_PEM, OCSP_SERVER).is_ok());
+        assert!(verify(&revocation, SERVER_PEM, &long).is_err());
+    }
+}
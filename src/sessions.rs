@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use rustls::NamedGroup;
+use rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue,
+};
+use rustls_pki_types::ServerName;
+
+type Hints = HashMap<String, u16>;
+
+/// Session store remembering across restarts which key exchange group each server
+/// picked, so handshakes after a restart don't pay for a `HelloRetryRequest`.
+///
+/// Only those hints are kept on disk, the sessions themselves stay in memory
+/// like with the default store: rustls doesn't expose a way to serialize them,
+/// so a restarted process can't resume them.
+///
+/// The file holds one `server-name group` pair per line, the group in hex,
+/// `#` starts a comment. It's written by a thread of its own, so that handshakes
+/// never wait on the disk, and is up to date once the store is dropped.
+#[derive(Debug)]
+pub struct KxHintStore {
+    sessions: ClientSessionMemoryCache,
+    hints: Mutex<Hints>,
+
+    /// Hands the hints to the writing thread each time they change.
+    writer: Option<Sender<Hints>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KxHintStore {
+    /// Opens the store kept in the file at `path`, which is created on the first write,
+    /// keeping up to `size` sessions in memory.
+    pub fn open(path: impl Into<PathBuf>, size: usize) -> io::Result<Self> {
+        let path = path.into();
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut hints = HashMap::new();
+
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+
+            let mut fields = line.split_whitespace();
+
+            if let (Some(server), Some(group)) = (fields.next(), fields.next()) {
+                match u16::from_str_radix(group, 16) {
+                    Ok(group) => hints.insert(server.to_ascii_lowercase(), group),
+                    Err(_) => continue,
+                };
+            }
+        }
+
+        let (writer, recv) = mpsc::channel::<Hints>();
+
+        let thread = thread::Builder::new()
+            .name("tunnel-sessions".to_string())
+            .spawn(move || {
+                while let Ok(mut hints) = recv.recv() {
+                    // Only the latest of the hints queued meanwhile is worth writing.
+                    while let Ok(newer) = recv.try_recv() {
+                        hints = newer;
+                    }
+
+                    if let Err(e) = save(&path, &hints) {
+                        log::warn!("couldn't record the key exchange groups of servers: {}", e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sessions: ClientSessionMemoryCache::new(size),
+            hints: Mutex::new(hints),
+            writer: Some(writer),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Rewrites the whole file, through a temporary one so it's never left half written.
+fn save(path: &Path, hints: &Hints) -> io::Result<()> {
+    let mut entries: Vec<_> = hints.iter().collect();
+    entries.sort();
+
+    let mut text = String::from("# key exchange groups picked by servers, kept by tunnel\n");
+
+    for (server, group) in entries {
+        text.push_str(&format!("{} {:04x}\n", server, group));
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+/// Waits for the last hints to be written.
+impl Drop for KxHintStore {
+    fn drop(&mut self) {
+        self.writer.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ClientSessionStore for KxHintStore {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        let server = server_name.to_str().to_ascii_lowercase();
+        let group = u16::from(group);
+
+        let mut hints = self.hints.lock().unwrap();
+
+        if hints.insert(server, group) == Some(group) {
+            return;
+        }
+
+        if let Some(ref writer) = self.writer {
+            let _ = writer.send(hints.clone());
+        }
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        let hints = self.hints.lock().unwrap();
+
+        hints
+            .get(&server_name.to_str().to_ascii_lowercase())
+            .map(|group| NamedGroup::from(*group))
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.sessions.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.sessions.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.sessions.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.sessions.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.sessions.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KxHintStore;
+    use rustls::NamedGroup;
+    use rustls::client::ClientSessionStore;
+    use rustls_pki_types::ServerName;
+
+    #[test]
+    fn kx_hints_survive_restart() {
+        let path = std::env::temp_dir().join(format!("tunnel-sessions-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let name = ServerName::try_from("example.com").unwrap();
+        let other = ServerName::try_from("example.org").unwrap();
+
+        let store = KxHintStore::open(&path, 16).unwrap();
+        assert_eq!(store.kx_hint(&name), None);
+
+        store.set_kx_hint(name.clone(), NamedGroup::X25519MLKEM768);
+        store.set_kx_hint(other.clone(), NamedGroup::secp256r1);
+
+        // Written by the time the store is dropped.
+        drop(store);
+
+        let store = KxHintStore::open(&path, 16).unwrap();
+        assert_eq!(store.kx_hint(&name), Some(NamedGroup::X25519MLKEM768));
+        assert_eq!(store.kx_hint(&other), Some(NamedGroup::secp256r1));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,238 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+/// Options of the TCP sockets connections are made over,
+/// the system defaults unless set.
+///
+/// Binding to an interface is only supported on Linux and Android,
+/// keepalive probes on them and on Apple's systems.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SocketOptions {
+    local_addr: Option<IpAddr>,
+    interface: Option<String>,
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    keepalive_probes: Option<(Duration, u32)>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects from `addr`, on a port picked by the system.
+    /// Addresses of the other family can't be reached anymore.
+    pub fn set_local_addr(&mut self, addr: IpAddr) -> &mut Self {
+        self.local_addr.replace(addr);
+        self
+    }
+
+    /// Sends through the network interface named `name`, like `eth1`,
+    /// whatever the routing table says.
+    pub fn set_interface(&mut self, name: &str) -> &mut Self {
+        self.interface.replace(name.to_string());
+        self
+    }
+
+    /// Turns Nagle's algorithm off, so small writes go out right away.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.nodelay.replace(nodelay);
+        self
+    }
+
+    /// Probes the peer once the connection has been idle for `idle`.
+    pub fn set_keepalive(&mut self, idle: Duration) -> &mut Self {
+        self.keepalive.replace(idle);
+        self
+    }
+
+    /// Probes every `interval` once probing started,
+    /// dropping the connection after `retries` unanswered probes.
+    /// Only applies along with `set_keepalive`.
+    pub fn set_keepalive_probes(&mut self, interval: Duration, retries: u32) -> &mut Self {
+        self.keepalive_probes.replace((interval, retries));
+        self
+    }
+
+    /// Sets `SO_SNDBUF`, which the system may round or double.
+    pub fn set_send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.send_buffer.replace(size);
+        self
+    }
+
+    /// Sets `SO_RCVBUF`, which the system may round or double.
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.recv_buffer.replace(size);
+        self
+    }
+
+    /// Whether only `nodelay` is set, which works on any socket.
+    fn connected_only(&self) -> bool {
+        self.local_addr.is_none()
+            && self.interface.is_none()
+            && self.keepalive.is_none()
+            && self.keepalive_probes.is_none()
+            && self.send_buffer.is_none()
+            && self.recv_buffer.is_none()
+    }
+
+    /// Starts a non-blocking connect to `addr` from a socket set up with the options.
+    pub(crate) fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match self.connected_only() {
+            true => mio::net::TcpStream::connect(addr).map(TcpStream::from)?,
+            false => self.socket(addr)?,
+        };
+
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+
+        Ok(socket)
+    }
+
+    /// Starts a non-blocking connect to `addr` from a new socket, set up first.
+    fn socket(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+
+        if let Some(ref name) = self.interface {
+            bind_interface(&socket, name)?;
+        }
+
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        if let Some(idle) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+
+            let keepalive = match self.keepalive_probes {
+                Some((interval, retries)) => probes(keepalive, interval, retries)?,
+                None => keepalive,
+            };
+
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        if let Some(ip) = self.local_addr {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+
+        match socket.connect(&addr.into()) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(socket.into())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_interface(socket: &Socket, name: &str) -> io::Result<()> {
+    socket.bind_device(Some(name.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_interface(_socket: &Socket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+fn probes(keepalive: TcpKeepalive, interval: Duration, retries: u32) -> io::Result<TcpKeepalive> {
+    Ok(keepalive.with_interval(interval).with_retries(retries))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+fn probes(
+    _keepalive: TcpKeepalive,
+    _interval: Duration,
+    _retries: u32,
+) -> io::Result<TcpKeepalive> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "keepalive probes are only supported on Linux and Apple's systems",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::SocketOptions;
+    use socket2::SockRef;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+    use std::time::Duration;
+
+    #[test]
+    fn options_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut options = SocketOptions::new();
+        options
+            .set_local_addr(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .set_nodelay(true)
+            .set_keepalive(Duration::from_secs(30))
+            .set_keepalive_probes(Duration::from_secs(5), 3)
+            .set_send_buffer_size(64 * 1024)
+            .set_recv_buffer_size(64 * 1024);
+
+        let socket = options.connect(addr).unwrap();
+        let (_accepted, peer) = listener.accept().unwrap();
+
+        assert_eq!(socket.local_addr().unwrap(), peer);
+        assert!(socket.nodelay().unwrap());
+
+        let socket = SockRef::from(&socket);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+
+        // Linux doubles the size asked for, to account for its bookkeeping.
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bound_to_interface() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut options = SocketOptions::new();
+        options.set_interface("lo");
+
+        let socket = options.connect(addr).unwrap();
+        let _ = listener.accept().unwrap();
+
+        let device = SockRef::from(&socket).device().unwrap();
+        assert_eq!(device.as_deref(), Some(&b"lo"[..]));
+    }
+
+    #[test]
+    fn unusable_options_fail() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+
+        let mut options = SocketOptions::new();
+        options.set_local_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(options.connect(addr).is_err(), "bound to the wrong family");
+
+        let mut options = SocketOptions::new();
+        options.set_interface("no-such-interface0");
+        assert!(options.connect(addr).is_err());
+    }
+}
@@ -11,6 +11,8 @@ use std::task::{Context, Poll};
 
 use log::debug;
 
+use super::info::TlsInfo;
+
 #[cfg(debug_assertions)]
 #[derive(Debug)]
 struct HandshakeMetrics {
@@ -20,6 +22,12 @@ struct HandshakeMetrics {
     read_blocked: bool,
 }
 
+/// Shutting down the write half of a transport,
+/// telling the peer nothing more will be sent.
+pub trait AsyncShutdown {
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
 pub struct SyncAdapter<'adapter, 'cx, IO> {
     io: &'adapter mut IO,
     cx: &'adapter mut Context<'cx>,
@@ -55,6 +63,19 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Write for SyncAdapter<'_, '_, IO> {
 pub struct Stream<IO> {
     io: IO,
     conn: ClientConnection,
+
+    /// Whether `close_notify` was queued already.
+    closing: bool,
+
+    /// Data to send as early data during the handshake.
+    early: Option<EarlyData>,
+}
+
+struct EarlyData {
+    data: Vec<u8>,
+
+    /// Amount of bytes taken as early data, the server allowing only so much.
+    written: usize,
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> Stream<IO> {
@@ -72,7 +93,12 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Stream<IO> {
             }
         };
 
-        let stream = Self { io, conn };
+        let stream = Self {
+            io,
+            conn,
+            closing: false,
+            early: None,
+        };
         Ok(Ready::Handshaking(stream))
     }
 
@@ -83,6 +109,48 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Stream<IO> {
         f(&self.conn)
     }
 
+    /// What the handshake settled on.
+    pub(crate) fn tls_info(&self) -> TlsInfo {
+        self.conn_fn(TlsInfo::new)
+    }
+
+    /// Hands as much of the early data as the server allows to rustls,
+    /// which sends it right after the `ClientHello`.
+    fn write_early(&mut self) {
+        let early = match self.early {
+            Some(ref mut early) => early,
+            None => return,
+        };
+
+        if let Some(mut writer) = self.conn.early_data()
+            && let Ok(n) = writer.write(&early.data[early.written..])
+        {
+            early.written += n;
+        }
+    }
+
+    /// Once the handshake is done, queues what couldn't go out as early data,
+    /// or all of it if the server rejected it.
+    fn finish_early(&mut self) -> io::Result<()> {
+        let early = match self.early.take() {
+            Some(early) => early,
+            None => return Ok(()),
+        };
+
+        let rest = match self.conn.is_early_data_accepted() {
+            true => &early.data[early.written..],
+            false => {
+                if early.written != 0 {
+                    debug!("early data rejected, sending it again");
+                }
+
+                &early.data[..]
+            }
+        };
+
+        self.conn.writer().write_all(rest)
+    }
+
     fn io_read(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
         let mut r = SyncAdapter {
             io: &mut self.io,
@@ -255,6 +323,9 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<IO> {
         while self.conn.wants_read() {
             match self.io_read(cx) {
                 Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+
+                // The reader tells a clean close from a truncated one.
+                Poll::Ready(Ok(0)) => break,
                 Poll::Ready(Ok(_ln)) => {
                     debug!("read len: {}", _ln);
                 }
@@ -264,8 +335,18 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<IO> {
             }
         }
 
+        // `Ok(0)` once the peer sent `close_notify`,
+        // `UnexpectedEof` if it closed the connection without it.
         return match self.conn.reader().read(buf) {
             Ok(n) => Poll::Ready(Ok(n)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                let err = io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "peer closed the connection without close_notify, data may be truncated",
+                );
+
+                Poll::Ready(Err(err))
+            }
             Err(e) => Poll::Ready(Err(e)),
         };
     }
@@ -309,11 +390,49 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<IO> {
     }
 }
 
+/// Sends `close_notify`, flushes it and shuts down the write half of the transport.
+/// Reading stays possible until the peer closes its side.
+impl<IO: AsyncRead + AsyncWrite + AsyncShutdown + Unpin> AsyncShutdown for Stream<IO> {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closing {
+            self.conn.send_close_notify();
+            self.closing = true;
+        }
+
+        while self.conn.wants_write() {
+            match self.io_write(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(_)) => {}
+            }
+        }
+
+        ready!(Pin::new(&mut self.io).poll_flush(cx))?;
+
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
 pub enum Ready<Rw> {
     Handshaking(Stream<Rw>),
     Done,
 }
 
+impl<Rw> Ready<Rw> {
+    /// Sends `data` as 0-RTT early data if the session resumed allows it,
+    /// the handshake only completes once all of it has been written either way.
+    ///
+    /// Early data can be replayed, `data` must be safe to process twice.
+    pub fn with_early_data(mut self, data: Vec<u8>) -> Self {
+        if let Ready::Handshaking(ref mut stream) = self {
+            stream.early = Some(EarlyData { data, written: 0 });
+        }
+
+        self
+    }
+}
+
 impl<Rw: AsyncRead + AsyncWrite + Unpin> Future for Ready<Rw> {
     type Output = io::Result<Stream<Rw>>;
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
@@ -327,12 +446,29 @@ impl<Rw: AsyncRead + AsyncWrite + Unpin> Future for Ready<Rw> {
         };
 
         while stream.conn.is_handshaking() {
-            dbg!(stream.conn.is_handshaking());
+            stream.write_early();
+
+            match stream.handshake(cx) {
+                Poll::Ready(Ok(_l)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    *me = Ready::Handshaking(stream);
+                    return Poll::Pending;
+                }
+            };
+        }
+
+        if let Err(e) = stream.finish_early() {
+            return Poll::Ready(Err(e));
+        }
+
+        // Whatever couldn't go out as early data.
+        while stream.conn.wants_write() {
             match stream.handshake(cx) {
                 Poll::Ready(Ok(_l)) => {}
                 Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                 Poll::Pending => {
-                    let _ = mem::replace(me, Ready::Handshaking(stream));
+                    *me = Ready::Handshaking(stream);
                     return Poll::Pending;
                 }
             };
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use lamp::runtime::Executor;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::tls_config::TlsConfig;
+use crate::tls_options::TlsOptions;
+
+/// User agent of the test clients.
+pub(crate) const USER_AGENT: &str = "tunnel-test/0.0.1";
+
+/// Answer of the test servers.
+pub(crate) const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+pub(crate) type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Runs `fut` to completion on a runtime of its own.
+pub(crate) fn run(fut: impl Future<Output = ()> + Send + 'static) {
+    let mut rt = Executor::new(4);
+
+    let res = rt.block_on(fut);
+    rt.shutdown();
+
+    assert!(res.is_ok(), "runtime shutdown abruptly due to an error");
+}
+
+/// Listens on a local port, `handler` accepting connections on a thread of its own.
+pub(crate) fn listen<T: Send + 'static>(
+    handler: impl FnOnce(TcpListener) -> T + Send + 'static,
+) -> (u16, JoinHandle<T>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    (port, thread::spawn(move || handler(listener)))
+}
+
+/// Like `listen`, `handler` serving the first connection only.
+pub(crate) fn serve<T: Send + 'static>(
+    handler: impl FnOnce(TcpStream) -> T + Send + 'static,
+) -> (u16, JoinHandle<T>) {
+    listen(|listener| handler(listener.accept().unwrap().0))
+}
+
+/// Config of the test servers, serving `server.pem`.
+/// Asks for a client certificate signed by the test CA if `client_auth` is set.
+pub(crate) fn server_config(client_auth: bool) -> ServerConfig {
+    let certs = CertificateDer::pem_slice_iter(include_bytes!("../testdata/server.pem"))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/server.key")).unwrap();
+
+    let provider = TlsOptions::new().provider().unwrap();
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .unwrap();
+    let builder = match client_auth {
+        false => builder.with_no_client_auth(),
+        true => {
+            let ca = CertificateDer::from_pem_slice(include_bytes!("../testdata/ca.pem"));
+
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.unwrap()).unwrap();
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .unwrap();
+
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    builder.with_single_cert(certs, key).unwrap()
+}
+
+/// Serves a single TLS connection with `server_config`, answering one request with `200 ok`.
+pub(crate) fn tls_server(client_auth: bool) -> (u16, JoinHandle<io::Result<()>>) {
+    tls_server_with(client_auth, |tls| {
+        let mut buf = [0u8; 1024];
+        let _ = tls.read(&mut buf)?;
+
+        tls.write_all(OK)?;
+        tls.flush()
+    })
+}
+
+/// Like `tls_server`, with `handler` serving the connection.
+pub(crate) fn tls_server_with(
+    client_auth: bool,
+    handler: impl FnOnce(&mut TlsServerStream) -> io::Result<()> + Send + 'static,
+) -> (u16, JoinHandle<io::Result<()>>) {
+    let config = Arc::new(server_config(client_auth));
+
+    listen(move |listener| {
+        let (sock, _) = listener.accept()?;
+
+        let conn = ServerConnection::new(config).unwrap();
+        let mut tls = StreamOwned::new(conn, sock);
+
+        handler(&mut tls)
+    })
+}
+
+/// TLS config trusting only the test CA.
+pub(crate) fn ca_config() -> TlsConfig {
+    let mut tls = TlsConfig::new();
+    tls.set_mozilla_roots(false)
+        .add_root_pem(include_bytes!("../testdata/ca.pem"))
+        .unwrap();
+
+    tls
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use lamp::time::{Sleep, sleep};
+
+/// Future completing once a duration has passed, built on lamp's timers.
+pub(crate) struct Delay {
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Delay {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            sleep: Box::pin(sleep(duration)),
+        }
+    }
+
+    pub(crate) fn until(deadline: Instant) -> Self {
+        Self::new(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.sleep.as_mut().poll(cx)
+    }
+}
+
+/// Polls an optional deadline, returning `true` once it has passed.
+pub(crate) fn expired(delay: &mut Option<Delay>, cx: &mut Context<'_>) -> bool {
+    match delay.as_mut() {
+        Some(delay) => Pin::new(delay).poll(cx).is_ready(),
+        None => false,
+    }
+}
+
+/// Future failing with a timeout of `phase` if `fut` isn't done in time.
+pub(crate) struct Timeout<F> {
+    fut: F,
+    delay: Option<Delay>,
+    phase: Phase,
+}
+
+impl<F> Timeout<F> {
+    /// The deadline starts from now, `None` means no deadline.
+    pub(crate) fn new(fut: F, duration: Option<Duration>, phase: Phase) -> Self {
+        Self {
+            fut,
+            delay: duration.map(Delay::new),
+            phase,
+        }
+    }
+}
+
+impl<F, T> Future for Timeout<F>
+where
+    F: Future<Output = io::Result<T>> + Unpin,
+{
+    type Output = io::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = &mut *self;
+
+        if expired(&mut me.delay, cx) {
+            return Poll::Ready(Err(elapsed(me.phase)));
+        }
+
+        Pin::new(&mut me.fut).poll(cx)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Phase of a connection or request that ran out of time.
+pub enum Phase {
+    Connect,
+    Handshake,
+    Write,
+    FirstByte,
+    Total,
+}
+
+#[derive(Debug)]
+/// Error carried by the `io::ErrorKind::TimedOut` errors of this crate.
+pub struct Elapsed(Phase);
+
+impl Elapsed {
+    pub fn phase(&self) -> Phase {
+        self.0
+    }
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} timed out", self.0)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+pub(crate) fn elapsed(phase: Phase) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, Elapsed(phase))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Deadlines of every phase of a request, `None` meaning no deadline.
+pub struct Timeouts {
+    /// Resolving the host and establishing the TCP connection.
+    pub connect: Option<Duration>,
+
+    /// The TLS handshake.
+    pub handshake: Option<Duration>,
+
+    /// Writing and flushing the request.
+    pub write: Option<Duration>,
+
+    /// Waiting for the first byte of the response once the request is written.
+    pub first_byte: Option<Duration>,
+
+    /// The whole request, from `Client::execute` to the full response.
+    pub total: Option<Duration>,
+}
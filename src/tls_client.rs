@@ -2,91 +2,155 @@ use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context, Poll, ready};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use lamp::io::{AsyncRead, AsyncWrite, TcpStream, TokenBearer};
+use lamp::io::{AsyncRead, AsyncWrite, TokenBearer};
 
-use rustls::{ClientConfig, RootCertStore};
+use rustls::ClientConfig;
 use rustls_pki_types::ServerName;
 
-use super::stream::{Ready, Stream};
+use super::connect::{Addrs, Connecting, Link, Tcp};
+use super::info::ConnectionInfo;
+use super::stream::{AsyncShutdown, Ready, Stream};
+use super::timer::{self, Delay, Phase};
+use super::url::{Host, Url};
 
-pub(crate) struct Resolving<'a> {
-    io: Ready<TcpStream>,
+enum State<IO> {
+    Connecting(Connecting<IO>),
+    Handshaking(Box<Ready<IO>>),
+    Done,
+}
+
+pub(crate) struct Resolving<IO = Tcp> {
+    state: State<IO>,
+    dns_name: ServerName<'static>,
     cfg: Arc<ClientConfig>,
-    url: &'a str,
+    url: Url,
+    handshake_timeout: Option<Duration>,
+    timer: Option<Delay>,
+    addrs: Option<Addrs>,
+    early_data: Option<Vec<u8>>,
 }
 
-impl<'a> Future for Resolving<'a> {
-    type Output = io::Result<TlsClient<'a>>;
+impl<IO: Link> Future for Resolving<IO> {
+    type Output = io::Result<TlsClient<IO>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let res = ready!(Pin::new(&mut self.io).poll(cx));
+        use std::mem;
 
-        let output = match res {
-            Err(e) => Poll::Ready(Err(e)),
+        loop {
+            match mem::replace(&mut self.state, State::Done) {
+                State::Done => panic!("polled after completion"),
 
-            Ok(stream) => {
-                let client = TlsClient {
-                    io: stream,
-                    cfg: Arc::clone(&self.cfg),
-                    url: self.url,
-                };
+                State::Connecting(mut tcp) => {
+                    let res = match tcp.as_mut().poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.state = State::Connecting(tcp);
+                            return Poll::Pending;
+                        }
+                    };
 
-                Poll::Ready(Ok(client))
-            }
-        };
+                    let link = res?;
+                    self.addrs = link.addrs();
+
+                    let mut io =
+                        Stream::create(link, self.dns_name.clone(), Arc::clone(&self.cfg))?;
+
+                    if let Some(data) = self.early_data.take() {
+                        io = io.with_early_data(data);
+                    }
+
+                    self.state = State::Handshaking(Box::new(io));
+                    self.timer = self.handshake_timeout.map(Delay::new);
+                }
+
+                State::Handshaking(mut io) => {
+                    if timer::expired(&mut self.timer, cx) {
+                        return Poll::Ready(Err(timer::elapsed(Phase::Handshake)));
+                    }
+
+                    let res = match Pin::new(&mut io).poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.state = State::Handshaking(io);
+                            return Poll::Pending;
+                        }
+                    };
+
+                    let output = match res {
+                        Err(e) => Poll::Ready(Err(e)),
+
+                        Ok(stream) => {
+                            let client = TlsClient {
+                                io: stream,
+                                cfg: Arc::clone(&self.cfg),
+                                url: self.url.clone(),
+                                addrs: self.addrs,
+                            };
+
+                            Poll::Ready(Ok(client))
+                        }
+                    };
 
-        output
+                    return output;
+                }
+            }
+        }
     }
 }
 
-pub(crate) struct TlsClient<'a> {
-    io: Stream<TcpStream>,
+pub(crate) struct TlsClient<IO = Tcp> {
+    io: Stream<IO>,
     cfg: Arc<ClientConfig>,
-    url: &'a str,
+    url: Url,
+    addrs: Option<Addrs>,
 }
 
-impl TlsClient<'_> {
+impl<IO: Link> TlsClient<IO> {
+    /// Waits for `link` to connect and runs the handshake with `server_name` over it,
+    /// sending `early_data` along with it when the session resumed allows.
     pub(crate) fn create(
-        conf: Option<ClientConfig>,
-        url: &'static str,
-    ) -> io::Result<Resolving<'static>> {
-        let cfg = match conf {
-            None => {
-                // make config
-                let root_store = RootCertStore {
-                    roots: webpki_roots::TLS_SERVER_ROOTS.into(),
-                };
-
-                let config = ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth();
-
-                Arc::new(config)
-            }
-
-            Some(config) => Arc::new(config),
-        };
-
-        let dns_name: ServerName<'static> = match url.try_into() {
-            Ok(name) => name,
-            Err(e) => {
-                let err = io::Error::new(io::ErrorKind::Other, e);
+        cfg: Arc<ClientConfig>,
+        url: &Url,
+        server_name: &Host,
+        link: Connecting<IO>,
+        handshake_timeout: Option<Duration>,
+        early_data: Option<Vec<u8>>,
+    ) -> io::Result<Resolving<IO>> {
+        let dns_name: ServerName<'static> = match server_name {
+            Host::Domain(domain) => match ServerName::try_from(domain.clone()) {
+                Ok(name) => name,
+                Err(e) => {
+                    let err = io::Error::other(e);
 
-                return Err(err);
-            }
+                    return Err(err);
+                }
+            },
+            Host::Ipv4(ip) => ServerName::from(*ip),
+            Host::Ipv6(ip) => ServerName::from(*ip),
         };
 
-        let tcp = std::net::TcpStream::connect(&format!("{}:443", url))?;
-        let lamp_tcp = TcpStream::from_std(tcp)?;
-        let io = Stream::create(lamp_tcp, dns_name, Arc::clone(&cfg))?;
+        Ok(Resolving {
+            state: State::Connecting(link),
+            dns_name,
+            cfg,
+            url: url.clone(),
+            handshake_timeout,
+            timer: None,
+            addrs: None,
+            early_data,
+        })
+    }
 
-        Ok(Resolving { io, cfg, url })
+    /// Details of the connection, the handshake being done.
+    pub(crate) fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(self.addrs, Some(self.io.tls_info()))
     }
 }
 
-impl AsyncRead for TlsClient<'_> {
+impl<IO: Link> AsyncRead for TlsClient<IO> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -96,7 +160,7 @@ impl AsyncRead for TlsClient<'_> {
     }
 }
 
-impl AsyncWrite for TlsClient<'_> {
+impl<IO: Link> AsyncWrite for TlsClient<IO> {
     fn poll_write<'w>(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'w>,
@@ -110,8 +174,108 @@ impl AsyncWrite for TlsClient<'_> {
     }
 }
 
-impl TokenBearer for TlsClient<'_> {
+impl<IO: Link> AsyncShutdown for TlsClient<IO> {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+impl<IO: Link + TokenBearer> TokenBearer for TlsClient<IO> {
     fn get_token(&self) -> mio::Token {
         self.io.get_token()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{OK, USER_AGENT, ca_config, run, tls_server_with};
+    use crate::url::Url;
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    #[test]
+    fn close_notify_on_shutdown() {
+        let (port, server) = tls_server_with(false, |tls| {
+            let mut buf = [0u8; 1024];
+            let _ = tls.read(&mut buf)?;
+
+            tls.write_all(OK)?;
+            tls.flush()?;
+
+            // A bare EOF, without close_notify, fails with `UnexpectedEof`.
+            match tls.read(&mut buf)? {
+                0 => Ok(()),
+                _ => Err(io::ErrorKind::InvalidData.into()),
+            }
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(ca_config())
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+
+            client.shutdown();
+
+            // The connection is closed by its own task.
+            for _ in 0..500 {
+                if server.is_finished() {
+                    break;
+                }
+
+                lamp::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            server.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn truncated_response_detected() {
+        let (port, server) = tls_server_with(false, |tls| {
+            let mut buf = [0u8; 1024];
+            let _ = tls.read(&mut buf)?;
+
+            tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello")?;
+            tls.flush()?;
+
+            // Gone halfway through the body, without close_notify.
+            tls.sock.shutdown(Shutdown::Both)
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(ca_config())
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let err = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(err.to_string().contains("close_notify"), "{}", err);
+        });
+
+        server.join().unwrap().unwrap();
+    }
+}
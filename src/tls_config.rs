@@ -0,0 +1,935 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Resumption, WebPkiServerVerifier,
+};
+use rustls::{ClientConfig, KeyLog, KeyLogFile, RootCertStore};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use super::keylog::KeyLogWriter;
+use super::pinning::{PinSet, PinningVerifier};
+use super::revocation::{Revocation, RevocationVerifier};
+use super::tls_options::TlsOptions;
+use super::tofu::{KnownHosts, TofuVerifier};
+
+/// A client certificate chain and its private key, used for mutual TLS.
+#[derive(Debug)]
+pub struct Identity {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Loads an identity from PEM data, the leaf certificate first.
+    ///
+    /// The key may be PKCS#8, PKCS#1 (RSA) or SEC1 (EC).
+    pub fn from_pem(certs: &[u8], key: &[u8]) -> io::Result<Self> {
+        let chain = CertificateDer::pem_slice_iter(certs)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_err)?;
+
+        if chain.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "no certificate found");
+
+            return Err(err);
+        }
+
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(pem_err)?;
+
+        Ok(Self { chain, key })
+    }
+
+    /// Loads an identity from a PEM certificate chain file and a PEM key file.
+    pub fn from_pem_files(certs: impl AsRef<Path>, key: impl AsRef<Path>) -> io::Result<Self> {
+        let certs = std::fs::read(certs)?;
+        let key = std::fs::read(key)?;
+
+        Self::from_pem(&certs, &key)
+    }
+
+    pub fn chain(&self) -> &[CertificateDer<'static>] {
+        &self.chain
+    }
+}
+
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Self {
+            chain: self.chain.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+fn no_sha256() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "crypto provider has no SHA-256")
+}
+
+pub(crate) fn pem_err(e: rustls_pki_types::pem::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Picks the client certificate presented to a server,
+/// `None` meaning the handshake goes on without one.
+pub trait ResolveIdentity: Send + Sync {
+    fn resolve(&self, server_name: &str) -> Option<Identity>;
+}
+
+/// A single identity is presented to every server.
+impl ResolveIdentity for Identity {
+    fn resolve(&self, _server_name: &str) -> Option<Identity> {
+        Some(self.clone())
+    }
+}
+
+/// Identities picked by server name, with an optional fallback.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityMap {
+    hosts: HashMap<String, Identity>,
+    default: Option<Identity>,
+}
+
+impl IdentityMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Presents `identity` to the server named `host`.
+    pub fn insert(&mut self, host: &str, identity: Identity) -> &mut Self {
+        self.hosts.insert(host.to_ascii_lowercase(), identity);
+        self
+    }
+
+    /// Presents `identity` to every server without an identity of its own.
+    pub fn set_default(&mut self, identity: Identity) -> &mut Self {
+        self.default.replace(identity);
+        self
+    }
+}
+
+impl ResolveIdentity for IdentityMap {
+    fn resolve(&self, server_name: &str) -> Option<Identity> {
+        self.hosts
+            .get(&server_name.to_ascii_lowercase())
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// CA bundles shipped by the usual distributions, first match wins.
+const SYSTEM_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+const SYSTEM_DIR: &str = "/etc/ssl/certs";
+
+/// Sessions kept by the default store, across every host.
+const DEFAULT_SESSIONS: usize = 256;
+
+/// Store of the configs not given one, shared so that a session stored
+/// by one client can be resumed by another of the process.
+fn default_sessions() -> Arc<dyn ClientSessionStore> {
+    static DEFAULT: OnceLock<Arc<ClientSessionMemoryCache>> = OnceLock::new();
+
+    let store = DEFAULT.get_or_init(|| Arc::new(ClientSessionMemoryCache::new(DEFAULT_SESSIONS)));
+    Arc::clone(store) as Arc<dyn ClientSessionStore>
+}
+
+/// Configs built by server name and port.
+type Built = HashMap<(String, u16), Arc<ClientConfig>>;
+
+/// Settings of the TLS handshakes of a client.
+#[derive(Clone)]
+pub struct TlsConfig {
+    identity: Option<Arc<dyn ResolveIdentity>>,
+
+    /// Whether the Mozilla roots of `webpki_roots` are trusted.
+    mozilla_roots: bool,
+
+    /// Trust anchors added on top of the Mozilla roots.
+    extra_roots: Arc<RootCertStore>,
+
+    pins: Option<Arc<PinSet>>,
+    revocation: Option<Arc<Revocation>>,
+    known_hosts: Option<Arc<KnownHosts>>,
+
+    options: TlsOptions,
+
+    /// Sessions kept to resume later handshakes.
+    sessions: Arc<dyn ClientSessionStore>,
+    early_data: bool,
+
+    /// Protocols offered with ALPN, none by default.
+    alpn_protocols: Vec<Vec<u8>>,
+
+    /// Where the secrets of handshakes are written, never by default.
+    key_log: Option<Arc<dyn KeyLog>>,
+
+    /// Configs built so far, by server name and port.
+    /// rustls only resumes a session with the config which stored it.
+    built: Arc<Mutex<Built>>,
+
+    /// Tells configs apart, so that pooled connections are only reused with the settings
+    /// they were made with. Clones share it, as do untouched default configs.
+    id: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            mozilla_roots: true,
+            extra_roots: Arc::new(RootCertStore::empty()),
+            pins: None,
+            revocation: None,
+            known_hosts: None,
+            options: TlsOptions::default(),
+            sessions: default_sessions(),
+            early_data: false,
+            alpn_protocols: Vec::new(),
+            key_log: None,
+            built: Arc::default(),
+            id: 0,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to trust the Mozilla roots bundled with the crate, on by default.
+    /// Turning them off leaves only the roots added to the config.
+    pub fn set_mozilla_roots(&mut self, enabled: bool) -> &mut Self {
+        self.mozilla_roots = enabled;
+        self.changed()
+    }
+
+    /// Trusts every certificate of a PEM bundle.
+    pub fn add_root_pem(&mut self, pem: &[u8]) -> io::Result<&mut Self> {
+        let certs = CertificateDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_err)?;
+
+        if certs.is_empty() {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "no certificate found");
+
+            return Err(err);
+        }
+
+        let roots = Arc::make_mut(&mut self.extra_roots);
+
+        for cert in certs {
+            roots
+                .add(cert)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        Ok(self.changed())
+    }
+
+    /// Trusts every certificate of a PEM bundle file.
+    pub fn add_root_file(&mut self, path: impl AsRef<Path>) -> io::Result<&mut Self> {
+        let pem = fs::read(path)?;
+
+        self.add_root_pem(&pem)
+    }
+
+    /// Trusts the certificates of every PEM file in a directory, like `SSL_CERT_DIR`.
+    /// Files and certificates which can't be parsed are skipped.
+    ///
+    /// Returns the amount of certificates added.
+    pub fn add_root_dir(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut added = 0;
+
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                added += self.add_parsable_file(&path);
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Trusts the certificate store of the operating system.
+    ///
+    /// `SSL_CERT_FILE` and `SSL_CERT_DIR` are honoured like OpenSSL does,
+    /// otherwise the bundle of the distribution and `/etc/ssl/certs` are used.
+    /// Fails with `io::ErrorKind::NotFound` if no certificate could be found.
+    pub fn load_system_roots(&mut self) -> io::Result<&mut Self> {
+        let file = env::var_os("SSL_CERT_FILE");
+        let dirs = env::var_os("SSL_CERT_DIR");
+
+        let found = match (file, dirs) {
+            (None, None) => {
+                let bundle = SYSTEM_BUNDLES.iter().find(|p| Path::new(p).is_file());
+
+                bundle.map_or(0, |path| self.add_parsable_file(Path::new(path)))
+                    + self.add_root_dir(SYSTEM_DIR).unwrap_or(0)
+            }
+
+            (file, dirs) => {
+                let from_file = file.map_or(0, |path| self.add_parsable_file(Path::new(&path)));
+
+                // Like with OpenSSL, `SSL_CERT_DIR` may list several directories.
+                let from_dirs: usize = dirs.map_or(0, |dirs| {
+                    env::split_paths(&dirs)
+                        .map(|dir| self.add_root_dir(dir).unwrap_or(0))
+                        .sum()
+                });
+
+                from_file + from_dirs
+            }
+        };
+
+        if found == 0 {
+            let err = io::Error::new(io::ErrorKind::NotFound, "no system certificates found");
+
+            return Err(err);
+        }
+
+        Ok(self)
+    }
+
+    /// Adds whatever certificates of a PEM file can be parsed,
+    /// system stores often hold a few a strict parser would reject.
+    fn add_parsable_file(&mut self, path: &Path) -> usize {
+        let pem = match fs::read(path) {
+            Ok(pem) => pem,
+            Err(_) => return 0,
+        };
+
+        let certs = CertificateDer::pem_slice_iter(&pem).filter_map(Result::ok);
+        let (added, _ignored) =
+            Arc::make_mut(&mut self.extra_roots).add_parsable_certificates(certs);
+
+        self.changed();
+        added
+    }
+
+    /// Requires the pinned keys from the hosts of `pins`.
+    pub fn set_pins(&mut self, pins: PinSet) -> &mut Self {
+        self.pins.replace(Arc::new(pins));
+        self.changed()
+    }
+
+    /// Checks whether server certificates were revoked.
+    ///
+    /// Only the server's own certificate is checked, not the intermediates above it,
+    /// and a stapled OCSP response without a next update is accepted however old it is.
+    pub fn set_revocation(&mut self, revocation: Revocation) -> &mut Self {
+        self.revocation.replace(Arc::new(revocation));
+        self.changed()
+    }
+
+    /// Trusts the first certificate each host serves and only that one after,
+    /// instead of verifying it against the roots and pins.
+    ///
+    /// Meant for self-signed devices, the store is kept to accept changed keys.
+    pub fn set_known_hosts(&mut self, store: Arc<KnownHosts>) -> &mut Self {
+        self.known_hosts.replace(store);
+        self.changed()
+    }
+
+    /// Authenticates to servers with the client certificate `resolver` picks.
+    pub fn set_identity(&mut self, resolver: impl ResolveIdentity + 'static) -> &mut Self {
+        self.identity.replace(Arc::new(resolver));
+        self.changed()
+    }
+
+    /// Restricts the protocol versions, cipher suites and key exchange groups
+    /// of the handshakes, or changes their crypto provider.
+    pub fn set_options(&mut self, options: TlsOptions) -> &mut Self {
+        self.options = options;
+        self.changed()
+    }
+
+    /// Offers `protocols` with ALPN, in order of preference.
+    ///
+    /// Only HTTP/1.1 is spoken, a list without `http/1.1` is rejected
+    /// as the server could pick a protocol we can't speak.
+    pub fn set_alpn_protocols(
+        &mut self,
+        protocols: impl IntoIterator<Item = impl Into<Vec<u8>>>,
+    ) -> io::Result<&mut Self> {
+        let protocols: Vec<Vec<u8>> = protocols.into_iter().map(Into::into).collect();
+
+        if !protocols.is_empty() && !protocols.iter().any(|p| p == b"http/1.1") {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only HTTP/1.1 is spoken, ALPN protocols must include http/1.1",
+            );
+
+            return Err(err);
+        }
+
+        self.alpn_protocols = protocols;
+        Ok(self.changed())
+    }
+
+    /// Keeps the sessions to resume in `store`, by default an in-memory cache
+    /// of 256 sessions shared by every config of the process.
+    ///
+    /// rustls doesn't expose a way to serialize sessions, so a custom store
+    /// can share them between clients of a process but not persist them.
+    /// `KxHintStore` persists what can be, the key exchange group of each server.
+    pub fn set_session_store(&mut self, store: Arc<dyn ClientSessionStore>) -> &mut Self {
+        self.sessions = store;
+        self.changed()
+    }
+
+    /// Sends the first request of a resumed connection as TLS 1.3 early data,
+    /// saving a round trip, off by default.
+    ///
+    /// Early data can be replayed by an attacker, so only `GET`, `HEAD`,
+    /// `OPTIONS` and `PUT` requests are sent that way. Requests the server
+    /// rejects as early data, or answers with `425 Too Early`,
+    /// are sent again once the handshake is done.
+    pub fn set_early_data(&mut self, enabled: bool) -> &mut Self {
+        self.early_data = enabled;
+        self.changed()
+    }
+
+    pub(crate) fn early_data(&self) -> bool {
+        self.early_data
+    }
+
+    /// Writes the secrets of every handshake to the file named by `SSLKEYLOGFILE`,
+    /// for Wireshark to decrypt captured traffic. Nothing is written if it isn't set.
+    ///
+    /// Anyone reading the file can decrypt the connections.
+    pub fn set_key_log_file(&mut self) -> &mut Self {
+        if env::var_os("SSLKEYLOGFILE").is_none() {
+            log::warn!("key log enabled but SSLKEYLOGFILE isn't set");
+        }
+
+        self.key_log.replace(Arc::new(KeyLogFile::new()));
+        self.changed()
+    }
+
+    /// Writes the secrets of every handshake to `sink`, in the NSS key log format.
+    pub fn set_key_log(&mut self, sink: impl Write + Send + 'static) -> &mut Self {
+        self.key_log.replace(Arc::new(KeyLogWriter::new(sink)));
+        self.changed()
+    }
+
+    /// Drops the configs built so far, they don't match the settings anymore.
+    fn changed(&mut self) -> &mut Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        self.built = Arc::default();
+        self.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The rustls config of handshakes with `server_name` on `port`,
+    /// built once and reused so sessions can be resumed.
+    pub(crate) fn client_config(
+        &self,
+        server_name: &str,
+        port: u16,
+    ) -> io::Result<Arc<ClientConfig>> {
+        let server_name = server_name.to_ascii_lowercase();
+        let mut built = self.built.lock().unwrap();
+
+        if let Some(config) = built.get(&(server_name.clone(), port)) {
+            return Ok(Arc::clone(config));
+        }
+
+        let config = self.build(&server_name, port)?;
+        built.insert((server_name, port), Arc::clone(&config));
+
+        Ok(config)
+    }
+
+    fn build(&self, server_name: &str, port: u16) -> io::Result<Arc<ClientConfig>> {
+        let mut root_store = RootCertStore::clone(&self.extra_roots);
+
+        if self.mozilla_roots {
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        let roots = Arc::new(root_store);
+        let builder = ClientConfig::builder_with_provider(self.options.provider()?)
+            .with_protocol_versions(&self.options.versions()?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .with_root_certificates(Arc::clone(&roots));
+
+        let identity = self
+            .identity
+            .as_ref()
+            .and_then(|resolver| resolver.resolve(server_name));
+
+        let mut config = match identity {
+            None => builder.with_no_client_auth(),
+            Some(Identity { chain, key }) => builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        };
+
+        if self.pins.is_some() || self.revocation.is_some() {
+            let provider = Arc::clone(config.crypto_provider());
+            let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+
+            let mut verifier: Arc<dyn ServerCertVerifier> = match self.revocation {
+                Some(ref revocation) => {
+                    let verifier = RevocationVerifier::new(
+                        Arc::clone(&roots),
+                        revocation,
+                        Arc::clone(&provider),
+                    )
+                    .map_err(invalid)?;
+
+                    Arc::new(verifier)
+                }
+
+                None => WebPkiServerVerifier::builder_with_provider(
+                    Arc::clone(&roots),
+                    Arc::clone(&provider),
+                )
+                .build()
+                .map_err(invalid)?,
+            };
+
+            if let Some(ref pins) = self.pins {
+                let pins = Arc::clone(pins);
+
+                verifier = match PinningVerifier::new(verifier, pins, roots, &provider) {
+                    Some(verifier) => Arc::new(verifier),
+                    None => return Err(no_sha256()),
+                };
+            }
+
+            config.dangerous().set_certificate_verifier(verifier);
+        }
+
+        if let Some(ref store) = self.known_hosts {
+            let provider = Arc::clone(config.crypto_provider());
+
+            let verifier = match TofuVerifier::new(Arc::clone(store), port, &provider) {
+                Some(verifier) => verifier,
+                None => return Err(no_sha256()),
+            };
+
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+        }
+
+        config.resumption = Resumption::store(Arc::clone(&self.sessions));
+        config.enable_early_data = self.early_data;
+        config.alpn_protocols = self.alpn_protocols.clone();
+
+        if let Some(ref key_log) = self.key_log {
+            config.key_log = Arc::clone(key_log);
+        }
+
+        Ok(Arc::new(config))
+    }
+}
+
+/// TLS configs picked by the host connected to, with a default for other hosts.
+///
+/// A pattern is either a host name or address, matched exactly,
+/// or `*.` followed by a domain, matching every name under it.
+/// Exact matches win, then the longest matching domain.
+#[derive(Clone, Default)]
+pub struct TlsPolicy {
+    hosts: HashMap<String, TlsConfig>,
+    domains: Vec<(String, TlsConfig)>,
+    default: TlsConfig,
+}
+
+impl TlsPolicy {
+    pub fn new(default: TlsConfig) -> Self {
+        Self {
+            default,
+            ..Self::default()
+        }
+    }
+
+    /// Uses `tls` for the hosts matching `pattern`.
+    pub fn insert(&mut self, pattern: &str, tls: TlsConfig) -> &mut Self {
+        let pattern = pattern.to_ascii_lowercase();
+
+        match pattern.strip_prefix("*.") {
+            Some(domain) => {
+                // Keeps the domains longest first, so the most specific one is found first.
+                let suffix = format!(".{}", domain);
+                self.domains.retain(|(known, _)| *known != suffix);
+
+                let index = self
+                    .domains
+                    .partition_point(|(known, _)| known.len() >= suffix.len());
+                self.domains.insert(index, (suffix, tls));
+            }
+
+            None => {
+                self.hosts.insert(pattern, tls);
+            }
+        }
+
+        self
+    }
+
+    /// Uses `tls` for the hosts no pattern matches.
+    pub fn set_default(&mut self, tls: TlsConfig) -> &mut Self {
+        self.default = tls;
+        self
+    }
+
+    /// The config of connections to `host`.
+    pub fn for_host(&self, host: &str) -> &TlsConfig {
+        let host = host.to_ascii_lowercase();
+
+        if let Some(tls) = self.hosts.get(&host) {
+            return tls;
+        }
+
+        self.domains
+            .iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()))
+            .map_or(&self.default, |(_, tls)| tls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identity, IdentityMap, ResolveIdentity, TlsConfig, TlsPolicy};
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{USER_AGENT, ca_config, listen, run, server_config, tls_server};
+    use crate::url::Url;
+    use rustls::ProtocolVersion;
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const CLIENT_PEM: &[u8] = include_bytes!("../testdata/client.pem");
+    const CLIENT_KEY: &[u8] = include_bytes!("../testdata/client.key");
+    const SERVER_PEM: &[u8] = include_bytes!("../testdata/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/server.key");
+
+    #[test]
+    fn identity_key_formats() {
+        assert!(Identity::from_pem(CLIENT_PEM, CLIENT_KEY).is_ok());
+
+        let sec1 = include_bytes!("../testdata/client.sec1.key");
+        assert!(Identity::from_pem(CLIENT_PEM, sec1).is_ok());
+
+        let pkcs1 = include_bytes!("../testdata/rsa.pkcs1.key");
+        assert!(Identity::from_pem(CLIENT_PEM, pkcs1).is_ok());
+
+        assert!(Identity::from_pem(b"", CLIENT_KEY).is_err());
+        assert!(Identity::from_pem(CLIENT_PEM, b"not a key").is_err());
+    }
+
+    #[test]
+    fn identity_per_server_name() {
+        let client = Identity::from_pem(CLIENT_PEM, CLIENT_KEY).unwrap();
+        let server = Identity::from_pem(SERVER_PEM, SERVER_KEY).unwrap();
+
+        let mut map = IdentityMap::new();
+        map.insert("API.internal", server.clone());
+
+        assert_eq!(map.resolve("api.internal").unwrap().chain(), server.chain());
+        assert!(map.resolve("example.com").is_none());
+
+        map.set_default(client.clone());
+        assert_eq!(map.resolve("example.com").unwrap().chain(), client.chain());
+    }
+
+    #[test]
+    fn client_config_presents_identity() {
+        let config = TlsConfig::new().client_config("localhost", 443).unwrap();
+        assert!(!config.client_auth_cert_resolver.has_certs());
+
+        let client = Identity::from_pem(CLIENT_PEM, CLIENT_KEY).unwrap();
+
+        let mut tls = TlsConfig::new();
+        tls.set_identity(client);
+
+        let config = tls.client_config("localhost", 443).unwrap();
+        assert!(config.client_auth_cert_resolver.has_certs());
+    }
+
+    #[test]
+    fn custom_roots() {
+        let mut tls = TlsConfig::new();
+        tls.set_mozilla_roots(false);
+
+        tls.add_root_pem(include_bytes!("../testdata/ca.pem"))
+            .unwrap();
+        assert_eq!(tls.extra_roots.len(), 1);
+
+        assert!(tls.add_root_pem(b"no certificates here").is_err());
+
+        // Keys, CRLs and OCSP responses are skipped,
+        // the CA, the forged CA and five leaf certificates kept.
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        assert_eq!(tls.add_root_dir(dir).unwrap(), 7);
+        assert_eq!(tls.extra_roots.len(), 8);
+
+        assert!(tls.client_config("localhost", 443).is_ok());
+    }
+
+    #[test]
+    fn client_config_reused() {
+        let mut tls = TlsConfig::new();
+
+        let first = tls.client_config("localhost", 443).unwrap();
+        assert!(Arc::ptr_eq(
+            &first,
+            &tls.client_config("LOCALHOST", 443).unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            &first,
+            &tls.client_config("example.com", 443).unwrap()
+        ));
+        assert!(!first.enable_early_data);
+
+        // Changing a setting builds new configs.
+        tls.set_early_data(true);
+
+        let changed = tls.client_config("localhost", 443).unwrap();
+        assert!(!Arc::ptr_eq(&first, &changed));
+        assert!(changed.enable_early_data);
+    }
+
+    #[test]
+    fn sessions_shared() {
+        let first = TlsConfig::new();
+        let second = TlsConfig::new();
+
+        assert!(Arc::ptr_eq(&first.sessions, &second.sessions));
+    }
+
+    #[test]
+    fn policy_by_host() {
+        let mut internal = TlsConfig::new();
+        internal.set_mozilla_roots(false);
+
+        let mut corp = TlsConfig::new();
+        corp.set_early_data(true);
+
+        let mut build = TlsConfig::new();
+        build.set_alpn_protocols(["http/1.1"]).unwrap();
+        assert!(build.set_alpn_protocols(["h2"]).is_err());
+
+        let mut policy = TlsPolicy::new(TlsConfig::new());
+        policy
+            .insert("*.corp.example", corp)
+            .insert("*.build.corp.example", build)
+            .insert("API.internal", internal);
+
+        assert!(!policy.for_host("api.internal").mozilla_roots);
+        assert!(policy.for_host("other.internal").mozilla_roots);
+
+        assert!(policy.for_host("git.corp.example").early_data());
+        assert!(policy.for_host("a.git.corp.example").early_data());
+        assert!(!policy.for_host("corp.example").early_data());
+        assert!(!policy.for_host("evilcorp.example").early_data());
+
+        let ci = policy.for_host("ci.build.corp.example");
+        assert_eq!(ci.alpn_protocols, [b"http/1.1".to_vec()]);
+        assert!(!ci.early_data());
+    }
+
+    #[test]
+    fn custom_root_and_client_cert() {
+        let (port, server) = tls_server(true);
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+            let identity =
+                Identity::from_pem_files(dir.join("client.pem"), dir.join("client.key")).unwrap();
+
+            let mut tls = TlsConfig::new();
+            tls.set_mozilla_roots(false)
+                .set_identity(identity)
+                .add_root_file(dir.join("ca.pem"))
+                .unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+
+            let info = resp.connection().expect("no connection details");
+            assert_eq!(info.peer_addr().unwrap().port(), port);
+            assert!(info.local_addr().unwrap().ip().is_loopback());
+
+            let tls = info.tls().expect("no tls details");
+            assert_eq!(tls.version(), Some(ProtocolVersion::TLSv1_3));
+            assert!(tls.cipher_suite().is_some());
+            assert_eq!(tls.peer_certificates().len(), 1);
+            assert!(!tls.resumed());
+        });
+
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn untrusted_root_rejected() {
+        let (port, server) = tls_server(false);
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            // Only the Mozilla roots are trusted, not the test CA.
+            let res = ClientBuilder::new(USER_AGENT).connect(&url).await;
+            assert!(res.is_err(), "certificate of an unknown CA was accepted");
+        });
+
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn tls_policy_per_host() {
+        let (trusted_port, trusted) = tls_server(false);
+        let (untrusted_port, untrusted) = tls_server(false);
+
+        // Only connections to 127.0.0.1 trust the test CA.
+        let mut policy = TlsPolicy::new(TlsConfig::new());
+        policy.insert("127.0.0.1", ca_config());
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", trusted_port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls_policy(policy.clone())
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+
+            let url = Url::parse(&format!("https://localhost:{}/", untrusted_port)).unwrap();
+
+            let res = ClientBuilder::new(USER_AGENT)
+                .tls_policy(policy)
+                .connect(&url)
+                .await;
+
+            assert!(res.is_err(), "default config trusted the test CA");
+        });
+
+        trusted.join().unwrap().unwrap();
+        assert!(untrusted.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn resumption_and_early_data() {
+        let mut config = server_config(false);
+        config.max_early_data_size = 16384;
+        let config = Arc::new(config);
+
+        // Serves three connections, rejecting early data on the last one,
+        // and tells which requests came in as early data.
+        let (port, server) = listen(move |listener| -> io::Result<Vec<bool>> {
+            let mut early = Vec::new();
+
+            for reject in [false, false, true] {
+                let (mut sock, _) = listener.accept()?;
+                let mut conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+                if reject {
+                    conn.reject_early_data();
+                }
+
+                let mut data = Vec::new();
+
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut sock)?;
+                }
+
+                if let Some(mut reader) = conn.early_data() {
+                    reader.read_to_end(&mut data)?;
+                }
+
+                let mut tls = rustls::StreamOwned::new(conn, sock);
+                early.push(!data.is_empty());
+
+                if data.is_empty() {
+                    let mut buf = [0u8; 1024];
+                    let _ = tls.read(&mut buf)?;
+                }
+
+                tls.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                )?;
+                tls.conn.send_close_notify();
+                tls.flush()?;
+            }
+
+            Ok(early)
+        });
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut tls = ca_config();
+            tls.set_early_data(true);
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let mut infos = Vec::new();
+
+            // Every response closes its connection, the next request opens a new one.
+            for _ in 0..3 {
+                let resp = client
+                    .execute(ReqBuilder::new(Method::GET))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(resp.code(), 200);
+
+                let info = resp.connection().unwrap().tls().unwrap();
+                infos.push((info.resumed(), info.early_data_accepted()));
+            }
+
+            assert_eq!(infos, [(false, false), (true, true), (true, false)]);
+            assert_eq!(server.join().unwrap().unwrap(), [false, true, false]);
+        });
+    }
+}
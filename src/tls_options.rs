@@ -0,0 +1,298 @@
+use std::io;
+use std::sync::Arc;
+
+use rustls::crypto::{CryptoProvider, SupportedKxGroup};
+use rustls::{CipherSuite, NamedGroup, ProtocolVersion, SupportedCipherSuite};
+use rustls::{SupportedProtocolVersion, version};
+
+/// Protocol versions rustls implements, newest first.
+const VERSIONS: &[&SupportedProtocolVersion] = &[&version::TLS13, &version::TLS12];
+
+/// Protocol versions, cipher suites, key exchange groups and crypto provider
+/// of the handshakes, the rustls defaults unless set.
+///
+/// The provider is aws-lc-rs with the `aws-lc-rs` feature, the default,
+/// or ring with the `ring` feature instead. aws-lc-rs prefers the hybrid
+/// post-quantum group `X25519MLKEM768`, falling back on classic groups.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    provider: Option<Arc<CryptoProvider>>,
+    min_version: Option<ProtocolVersion>,
+    cipher_suites: Option<Vec<CipherSuite>>,
+    kx_groups: Option<Vec<NamedGroup>>,
+}
+
+/// A provider along with everything it implements,
+/// some of which it doesn't offer by default.
+struct Choices {
+    provider: CryptoProvider,
+    cipher_suites: Vec<SupportedCipherSuite>,
+    kx_groups: Vec<&'static dyn SupportedKxGroup>,
+}
+
+// With both, rustls has no process-wide default provider to pick from
+// and `ClientConfig::builder()` panics.
+#[cfg(all(feature = "aws-lc-rs", feature = "ring"))]
+compile_error!(
+    "features `aws-lc-rs` and `ring` are mutually exclusive, use `default-features = false` for `ring`"
+);
+
+#[cfg(feature = "aws-lc-rs")]
+fn builtin() -> io::Result<Choices> {
+    use rustls::crypto::aws_lc_rs as provider;
+
+    Ok(Choices {
+        provider: provider::default_provider(),
+        cipher_suites: provider::ALL_CIPHER_SUITES.to_vec(),
+        kx_groups: provider::ALL_KX_GROUPS.to_vec(),
+    })
+}
+
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+fn builtin() -> io::Result<Choices> {
+    use rustls::crypto::ring as provider;
+
+    Ok(Choices {
+        provider: provider::default_provider(),
+        cipher_suites: provider::ALL_CIPHER_SUITES.to_vec(),
+        kx_groups: provider::ALL_KX_GROUPS.to_vec(),
+    })
+}
+
+/// Without either feature, the provider installed for the process is used.
+#[cfg(not(any(feature = "aws-lc-rs", feature = "ring")))]
+fn builtin() -> io::Result<Choices> {
+    match CryptoProvider::get_default() {
+        Some(provider) => Ok(Choices::of(provider)),
+        None => {
+            let err = io::Error::new(
+                io::ErrorKind::NotFound,
+                "no crypto provider, enable the aws-lc-rs or ring feature or install one",
+            );
+
+            Err(err)
+        }
+    }
+}
+
+impl Choices {
+    fn of(provider: &CryptoProvider) -> Self {
+        Self {
+            provider: provider.clone(),
+            cipher_suites: provider.cipher_suites.clone(),
+            kx_groups: provider.kx_groups.clone(),
+        }
+    }
+}
+
+/// Picks what's `wanted` out of `available`, in the order it's wanted.
+fn pick<T: Copy, N: PartialEq + std::fmt::Debug>(
+    wanted: &[N],
+    available: &[T],
+    name: impl Fn(&T) -> N,
+    what: &str,
+) -> io::Result<Vec<T>> {
+    wanted
+        .iter()
+        .map(
+            |wanted| match available.iter().find(|t| name(t) == *wanted) {
+                Some(t) => Ok(*t),
+                None => {
+                    let msg = format!("unsupported {}: {:?}", what, wanted);
+
+                    Err(io::Error::new(io::ErrorKind::Unsupported, msg))
+                }
+            },
+        )
+        .collect()
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `provider` instead of the one picked by the cargo features.
+    /// Suites and groups are then picked among the ones it offers.
+    pub fn set_provider(&mut self, provider: Arc<CryptoProvider>) -> &mut Self {
+        self.provider.replace(provider);
+        self
+    }
+
+    /// Refuses protocol versions older than `version`,
+    /// `ProtocolVersion::TLSv1_3` allowing only TLS 1.3.
+    pub fn set_min_version(&mut self, version: ProtocolVersion) -> &mut Self {
+        self.min_version.replace(version);
+        self
+    }
+
+    /// Offers only `suites`, in order of preference.
+    pub fn set_cipher_suites(
+        &mut self,
+        suites: impl IntoIterator<Item = CipherSuite>,
+    ) -> &mut Self {
+        self.cipher_suites.replace(suites.into_iter().collect());
+        self
+    }
+
+    /// Offers only the key exchange `groups`, in order of preference.
+    /// Hybrid post-quantum groups like `NamedGroup::X25519MLKEM768`
+    /// are available with the aws-lc-rs provider.
+    pub fn set_kx_groups(&mut self, groups: impl IntoIterator<Item = NamedGroup>) -> &mut Self {
+        self.kx_groups.replace(groups.into_iter().collect());
+        self
+    }
+
+    /// The provider with only the suites and groups asked for.
+    pub(crate) fn provider(&self) -> io::Result<Arc<CryptoProvider>> {
+        let choices = match self.provider {
+            Some(ref provider) => Choices::of(provider),
+            None => builtin()?,
+        };
+
+        let mut provider = choices.provider;
+
+        if let Some(ref suites) = self.cipher_suites {
+            provider.cipher_suites = pick(
+                suites,
+                &choices.cipher_suites,
+                |suite| suite.suite(),
+                "cipher suite",
+            )?;
+        }
+
+        if let Some(ref groups) = self.kx_groups {
+            provider.kx_groups = pick(
+                groups,
+                &choices.kx_groups,
+                |group| group.name(),
+                "key exchange group",
+            )?;
+        }
+
+        Ok(Arc::new(provider))
+    }
+
+    /// Protocol versions allowed, newest first.
+    pub(crate) fn versions(&self) -> io::Result<Vec<&'static SupportedProtocolVersion>> {
+        let min = self.min_version.map_or(0, u16::from);
+
+        let versions: Vec<_> = VERSIONS
+            .iter()
+            .copied()
+            .filter(|v| u16::from(v.version) >= min)
+            .collect();
+
+        if versions.is_empty() {
+            let err = io::Error::new(io::ErrorKind::Unsupported, "no protocol version allowed");
+
+            return Err(err);
+        }
+
+        Ok(versions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsOptions;
+    use rustls::{CipherSuite, NamedGroup, ProtocolVersion};
+
+    #[test]
+    fn min_version() {
+        let mut options = TlsOptions::new();
+        assert_eq!(options.versions().unwrap().len(), 2);
+
+        options.set_min_version(ProtocolVersion::TLSv1_3);
+        let versions = options.versions().unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version, ProtocolVersion::TLSv1_3);
+
+        options.set_min_version(ProtocolVersion::Unknown(0x0305));
+        assert!(options.versions().is_err());
+    }
+
+    #[test]
+    fn suites_and_groups_in_order() {
+        let mut options = TlsOptions::new();
+        options
+            .set_cipher_suites([
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS13_AES_128_GCM_SHA256,
+            ])
+            .set_kx_groups([NamedGroup::secp384r1, NamedGroup::X25519]);
+
+        let provider = options.provider().unwrap();
+
+        let suites: Vec<_> = provider.cipher_suites.iter().map(|s| s.suite()).collect();
+        assert_eq!(
+            suites,
+            [
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS13_AES_128_GCM_SHA256,
+            ]
+        );
+
+        let groups: Vec<_> = provider.kx_groups.iter().map(|g| g.name()).collect();
+        assert_eq!(groups, [NamedGroup::secp384r1, NamedGroup::X25519]);
+
+        options.set_kx_groups([NamedGroup::FFDHE8192]);
+        assert!(options.provider().is_err());
+    }
+
+    #[cfg(feature = "aws-lc-rs")]
+    #[test]
+    fn post_quantum_preferred() {
+        let provider = TlsOptions::new().provider().unwrap();
+
+        assert_eq!(provider.kx_groups[0].name(), NamedGroup::X25519MLKEM768);
+        assert!(provider.kx_groups.len() > 1);
+    }
+
+    #[cfg(feature = "aws-lc-rs")]
+    #[test]
+    fn tls13_only_with_hybrid_group() {
+        use crate::http1::client::{ClientBuilder, Method};
+        use crate::http1::request::ReqBuilder;
+        use crate::testing::{USER_AGENT, ca_config, run, tls_server};
+        use crate::url::Url;
+
+        let (port, server) = tls_server(false);
+
+        let mut options = TlsOptions::new();
+        options
+            .set_min_version(ProtocolVersion::TLSv1_3)
+            .set_cipher_suites([CipherSuite::TLS13_CHACHA20_POLY1305_SHA256])
+            .set_kx_groups([NamedGroup::X25519MLKEM768]);
+
+        let mut tls = ca_config();
+        tls.set_options(options);
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            let info = resp.connection().unwrap().tls().unwrap();
+
+            assert_eq!(info.version(), Some(ProtocolVersion::TLSv1_3));
+            assert_eq!(
+                info.cipher_suite(),
+                Some(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+            );
+            assert_eq!(info.key_exchange_group(), Some(NamedGroup::X25519MLKEM768));
+        });
+
+        server.join().unwrap().unwrap();
+    }
+}
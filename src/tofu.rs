@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::hash::Hash;
+use rustls::crypto::{
+    CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+};
+use rustls::{CertificateError, DigitallySignedStruct, Error, OtherError, SignatureScheme};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+
+use super::pinning;
+
+/// Fingerprints of the leaf certificates seen from each host and port,
+/// trusted on first use like SSH's `known_hosts`.
+///
+/// The file holds one `host sha256-hex` pair per line, `#` starts a comment.
+/// Like OpenSSH, a host on another port than the default one, 443, is written `[host]:port`.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    hosts: Mutex<HashMap<String, String>>,
+}
+
+impl KnownHosts {
+    /// Opens the store kept in the file at `path`, which is created on the first write.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut hosts = HashMap::new();
+
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+
+            let mut fields = line.split_whitespace();
+
+            if let (Some(host), Some(fingerprint)) = (fields.next(), fields.next()) {
+                if !is_fingerprint(fingerprint) {
+                    log::warn!("skipping {} in {:?}, not a fingerprint", host, path);
+                    continue;
+                }
+
+                hosts.insert(host.to_ascii_lowercase(), fingerprint.to_ascii_lowercase());
+            }
+        }
+
+        Ok(Self {
+            path: Some(path),
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    /// A store only kept for the lifetime of the process.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fingerprint trusted for `host` on `port`, if it was seen before.
+    pub fn fingerprint(&self, host: &str, port: u16) -> Option<String> {
+        let hosts = self.hosts.lock().unwrap();
+
+        hosts.get(&entry(host, port)).cloned()
+    }
+
+    /// Trusts `fingerprint`, the hex of a SHA-256, for `host` on `port` from now on,
+    /// replacing the one known so far.
+    /// This is how a host whose key legitimately changed is accepted again.
+    pub fn accept(&self, host: &str, port: u16, fingerprint: &str) -> io::Result<()> {
+        if !is_fingerprint(fingerprint) {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a fingerprint is the hex of a SHA-256",
+            );
+
+            return Err(err);
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.insert(entry(host, port), fingerprint.to_ascii_lowercase());
+
+        self.save(&hosts)
+    }
+
+    /// Forgets `host` on `port`, the next certificate it serves is trusted on first use again.
+    pub fn forget(&self, host: &str, port: u16) -> io::Result<()> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.remove(&entry(host, port));
+
+        self.save(&hosts)
+    }
+
+    /// Compares `fingerprint` to the one known for `host`, recording it if there's none.
+    fn check(&self, host: &str, fingerprint: &str) -> Result<(), KeyChanged> {
+        let mut hosts = self.hosts.lock().unwrap();
+
+        match hosts.get(host) {
+            Some(known) if known == fingerprint => Ok(()),
+
+            Some(known) => Err(KeyChanged {
+                host: host.to_string(),
+                known: known.clone(),
+                presented: fingerprint.to_string(),
+                path: self.path.clone(),
+            }),
+
+            None => {
+                hosts.insert(host.to_string(), fingerprint.to_string());
+
+                if let Err(e) = self.save(&hosts) {
+                    log::warn!("couldn't record the fingerprint of {}: {}", host, e);
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Rewrites the whole file, through a temporary one so it's never left half written.
+    fn save(&self, hosts: &HashMap<String, String>) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut entries: Vec<_> = hosts.iter().collect();
+        entries.sort();
+
+        let mut text = String::from("# hosts trusted on first use by tunnel\n");
+
+        for (host, fingerprint) in entries {
+            text.push_str(&format!("{} {}\n", host, fingerprint));
+        }
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Name of the entry of `host` on `port`.
+fn entry(host: &str, port: u16) -> String {
+    let host = host.to_ascii_lowercase();
+
+    match port {
+        443 => host,
+        _ => format!("[{}]:{}", host, port),
+    }
+}
+
+/// Whether `text` is the hex of a SHA-256.
+fn is_fingerprint(text: &str) -> bool {
+    text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A host served a certificate other than the one trusted for it.
+#[derive(Debug)]
+pub struct KeyChanged {
+    host: String,
+    known: String,
+    presented: String,
+    path: Option<PathBuf>,
+}
+
+impl KeyChanged {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Fingerprint of the certificate the host served this time.
+    pub fn presented(&self) -> &str {
+        &self.presented
+    }
+}
+
+impl fmt::Display for KeyChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CERTIFICATE OF {} HAS CHANGED, someone could be intercepting the connection. \
+             Known fingerprint {}, presented {}",
+            self.host, self.known, self.presented
+        )?;
+
+        if let Some(path) = self.path.as_deref().and_then(Path::to_str) {
+            write!(f, ", accept the new one or remove the host from {}", path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for KeyChanged {}
+
+/// Verifier trusting the first certificate a host serves and only that one.
+///
+/// Neither the chain nor the name in the certificate are checked,
+/// which is what makes it usable with self-signed devices.
+pub(crate) struct TofuVerifier {
+    store: Arc<KnownHosts>,
+    port: u16,
+    supported: WebPkiSupportedAlgorithms,
+    sha256: &'static dyn Hash,
+}
+
+impl TofuVerifier {
+    /// Returns `None` if `provider` has no SHA-256 implementation.
+    pub(crate) fn new(
+        store: Arc<KnownHosts>,
+        port: u16,
+        provider: &CryptoProvider,
+    ) -> Option<Self> {
+        Some(Self {
+            store,
+            port,
+            supported: provider.signature_verification_algorithms,
+            sha256: pinning::sha256(provider)?,
+        })
+    }
+}
+
+impl fmt::Debug for TofuVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TofuVerifier")
+            .field("store", &self.store)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// Lowercase hex of the SHA-256 of a certificate.
+fn fingerprint(sha256: &dyn Hash, cert: &CertificateDer<'_>) -> String {
+    sha256
+        .hash(cert.as_ref())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let host = entry(&server_name.to_str(), self.port);
+        let fingerprint = fingerprint(self.sha256, end_entity);
+
+        match self.store.check(&host, &fingerprint) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(changed) => {
+                log::error!("{}", changed);
+
+                let err = OtherError(Arc::new(changed));
+                Err(Error::InvalidCertificate(CertificateError::Other(err)))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.supported)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.supported)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.supported.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KnownHosts, TofuVerifier};
+    use crate::http1::client::{ClientBuilder, Method};
+    use crate::http1::request::ReqBuilder;
+    use crate::testing::{USER_AGENT, run, tls_server};
+    use crate::tls_config::TlsConfig;
+    use crate::tls_options::TlsOptions;
+    use crate::url::Url;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+    use std::sync::Arc;
+
+    fn verify(store: &Arc<KnownHosts>, port: u16, pem: &[u8]) -> bool {
+        let provider = TlsOptions::new().provider().unwrap();
+        let verifier = TofuVerifier::new(Arc::clone(store), port, &provider).unwrap();
+
+        let cert = CertificateDer::from_pem_slice(pem).unwrap();
+        let name = ServerName::try_from("lab-device").unwrap();
+
+        verifier
+            .verify_server_cert(&cert, &[], &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        let server = include_bytes!("../testdata/server.pem");
+        let other = include_bytes!("../testdata/client.pem");
+
+        let path = std::env::temp_dir().join(format!("tunnel-known-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Arc::new(KnownHosts::open(&path).unwrap());
+
+        assert!(
+            verify(&store, 443, server),
+            "first certificate wasn't trusted"
+        );
+        assert!(verify(&store, 443, server));
+        assert!(
+            !verify(&store, 443, other),
+            "changed certificate was trusted"
+        );
+
+        // Another port is another server.
+        assert!(verify(&store, 8443, other));
+        assert!(!verify(&store, 8443, server));
+
+        // The fingerprints survive a restart.
+        let store = Arc::new(KnownHosts::open(&path).unwrap());
+        let known = store.fingerprint("LAB-DEVICE", 443).unwrap();
+        assert_eq!(known.len(), 64);
+        assert!(store.fingerprint("lab-device", 8443).is_some());
+        assert!(!verify(&store, 443, other));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("\n[lab-device]:8443 "));
+
+        // Accepting the new key replaces the old one.
+        store.forget("lab-device", 443).unwrap();
+        assert!(verify(&store, 443, other));
+        assert!(!verify(&store, 443, server));
+
+        assert!(
+            store
+                .accept("lab-device", 443, "not a fingerprint")
+                .is_err()
+        );
+        assert!(store.accept("lab-device", 443, &known[1..]).is_err());
+
+        store.accept("lab-device", 443, &known).unwrap();
+        assert!(verify(&store, 443, server));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn trust_on_first_use_connection() {
+        let (port, server) = tls_server(false);
+
+        let store = Arc::new(KnownHosts::in_memory());
+        let known = Arc::clone(&store);
+
+        run(async move {
+            let url = Url::parse(&format!("https://127.0.0.1:{}/", port)).unwrap();
+
+            // The test CA isn't trusted, the certificate is accepted on first use.
+            let mut tls = TlsConfig::new();
+            tls.set_known_hosts(known);
+
+            let mut client = ClientBuilder::new(USER_AGENT)
+                .tls(tls)
+                .connect(&url)
+                .await
+                .unwrap();
+
+            let resp = client
+                .execute(ReqBuilder::new(Method::GET))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resp.code(), 200);
+        });
+
+        server.join().unwrap().unwrap();
+
+        assert!(store.fingerprint("127.0.0.1", port).is_some());
+        assert!(store.fingerprint("127.0.0.1", 443).is_none());
+    }
+}